@@ -0,0 +1,59 @@
+//! A stand-in for the external console of a game server, to try pushing votes
+//! without running one:
+//!
+//! ```sh
+//! cargo run --example fake_econ -- 127.0.0.1:8303 secret
+//! ```
+//!
+//! It answers like the real econ and prints every command it receives.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+pub fn handle(stream: TcpStream, password: &str) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+
+    writer.write_all(b"Enter password:\r\n")?;
+    match lines.next() {
+        Some(Ok(line)) if line.trim_end() == password => {
+            writer.write_all(
+                b"Authentication successful. External console access granted.\r\n",
+            )?;
+        }
+        _ => {
+            writer.write_all(b"Wrong password 1/3.\r\n")?;
+            println!("{}: wrong password", peer);
+            return Ok(());
+        }
+    }
+
+    for line in lines {
+        let line = line?;
+        let command = line.trim_end();
+        println!("{}: {}", peer, command);
+        writeln!(writer, "[Console]: executed '{}'\r", command)?;
+    }
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let address = args.next().unwrap_or_else(|| "127.0.0.1:8303".to_owned());
+    let password = args.next().unwrap_or_else(|| "secret".to_owned());
+
+    let listener = TcpListener::bind(&address)?;
+    println!("Fake econ listening on {}", address);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let password = password.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &password) {
+                eprintln!("{}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -1,3 +1,9 @@
+//! Every request is authenticated with an API key in the `x-api-key` header,
+//! either from the keys file, see [`crate::keys`], or created through the
+//! API, see [`crate::stored_keys`]. Routes take [`ApiKey`] if any key
+//! will do, or one of the role guards [`Mapper`], [`Tester`] and [`Admin`].
+
+use rocket::serde::{Deserialize, Serialize};
 use rocket::{
     http::Status,
     request::{self, FromRequest, Outcome},
@@ -10,8 +16,55 @@ use rocket_okapi::{
     gen::OpenApiGenerator,
     request::{OpenApiFromRequest, RequestHeaderInput},
 };
+use schemars::JsonSchema;
+use structsy_derive::PersistentEmbedded;
+use strum::{AsRefStr, EnumString};
+
+#[derive(
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PersistentEmbedded,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    EnumString,
+    AsRefStr,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Role {
+    /// Creates and uploads their own maps.
+    Mapper,
+    /// Approves, declines and reviews maps.
+    Tester,
+    /// Publishes, recalls and deletes maps and manages keys. Admins may do
+    /// everything the other roles may.
+    Admin,
+}
+
+pub struct ApiKey {
+    label: String,
+    roles: Vec<Role>,
+    request_id: String,
+}
+
+impl ApiKey {
+    /// The label of the key, which does not leak the key itself.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// The id of the request the key was sent with.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
 
-pub struct ApiKey(String);
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role) || self.roles.contains(&Role::Admin)
+    }
+}
 
 // Implement the actual checks for the authentication
 #[rocket::async_trait]
@@ -21,18 +74,35 @@ impl<'a> FromRequest<'a> for ApiKey {
         request: &'a request::Request<'_>,
     ) -> request::Outcome<Self, Self::Error> {
         if crate::CONFIG.dev {
-            Outcome::Success(ApiKey(format!("")))
+            Outcome::Success(ApiKey {
+                label: "dev".to_owned(),
+                roles: vec![Role::Admin],
+                request_id: crate::audit::request_id(request).to_owned(),
+            })
         } else {
             // Get the key from the http header
             match request.headers().get_one("x-api-key") {
                 Some(key) => {
-                    if crate::CONFIG.apikeys.iter().any(|k| k == key) {
-                        Outcome::Success(ApiKey(key.to_owned()))
-                    } else {
-                        Outcome::Failure((
+                    let found = match crate::CONFIG.apikeys.find(key).await {
+                        Some(found) => Some(found),
+                        None => request
+                            .rocket()
+                            .state::<crate::CustomState>()
+                            .and_then(|state| {
+                                crate::stored_keys::find(&state.db, key)
+                            }),
+                    };
+                    match found {
+                        Some((label, roles)) => Outcome::Success(ApiKey {
+                            label,
+                            roles,
+                            request_id: crate::audit::request_id(request)
+                                .to_owned(),
+                        }),
+                        None => Outcome::Failure((
                             Status::Unauthorized,
                             "Api key is invalid.",
-                        ))
+                        )),
                     }
                 }
                 None => Outcome::Failure((
@@ -44,32 +114,46 @@ impl<'a> FromRequest<'a> for ApiKey {
     }
 }
 
+/// The security scheme of the API key, or of a key with the given role.
+fn security_input(role: Option<Role>) -> RequestHeaderInput {
+    let (name, description) = match role {
+        Some(role) => (
+            format!("ApiKeyAuth-{}", role.as_ref()),
+            format!(
+                "Requires an API key with the {} role to access.",
+                role.as_ref()
+            ),
+        ),
+        None => (
+            "ApiKeyAuth".to_owned(),
+            "Requires an API key to access.".to_owned(),
+        ),
+    };
+    // Setup global requirement for Security scheme
+    let security_scheme = SecurityScheme {
+        description: Some(description),
+        data: SecuritySchemeData::ApiKey {
+            name: "x-api-key".to_owned(),
+            location: "header".to_owned(),
+        },
+        extensions: Object::default(),
+    };
+    // Add the requirement for this route/endpoint
+    // This can change between routes.
+    let mut security_req = SecurityRequirement::new();
+    // Each security requirement needs to be met before access is allowed.
+    security_req.insert(name.clone(), Vec::new());
+    // These vvvvvvv-----^^^^^^^^^^ values need to match exactly!
+    RequestHeaderInput::Security(name, security_scheme, security_req)
+}
+
 impl<'a> OpenApiFromRequest<'a> for ApiKey {
     fn from_request_input(
         _gen: &mut OpenApiGenerator,
         _name: String,
         _required: bool,
     ) -> rocket_okapi::Result<RequestHeaderInput> {
-        // Setup global requirement for Security scheme
-        let security_scheme = SecurityScheme {
-            description: Some("Requires an API key to access.".to_owned()),
-            data: SecuritySchemeData::ApiKey {
-                name: "x-api-key".to_owned(),
-                location: "header".to_owned(),
-            },
-            extensions: Object::default(),
-        };
-        // Add the requirement for this route/endpoint
-        // This can change between routes.
-        let mut security_req = SecurityRequirement::new();
-        // Each security requirement needs to be met before access is allowed.
-        security_req.insert("ApiKeyAuth".to_owned(), Vec::new());
-        // These vvvvvvv-----^^^^^^^^^^ values need to match exactly!
-        Ok(RequestHeaderInput::Security(
-            "ApiKeyAuth".to_owned(),
-            security_scheme,
-            security_req,
-        ))
+        Ok(security_input(None))
     }
 
     // Optionally add responses
@@ -137,3 +221,78 @@ impl<'a> OpenApiFromRequest<'a> for ApiKey {
         }
     }
 }
+
+/// Defines a request guard which only accepts keys with the given role and
+/// answers `403 Forbidden` to all others.
+macro_rules! role_guard {
+    ($(#[$doc:meta])* $name:ident, $role:expr) => {
+        $(#[$doc])*
+        pub struct $name(pub ApiKey);
+
+        impl std::ops::Deref for $name {
+            type Target = ApiKey;
+
+            fn deref(&self) -> &ApiKey {
+                &self.0
+            }
+        }
+
+        #[rocket::async_trait]
+        impl<'a> FromRequest<'a> for $name {
+            type Error = &'static str;
+            async fn from_request(
+                request: &'a request::Request<'_>,
+            ) -> request::Outcome<Self, Self::Error> {
+                match ApiKey::from_request(request).await {
+                    Outcome::Success(key) if key.has_role($role) => {
+                        Outcome::Success($name(key))
+                    }
+                    Outcome::Success(_) => Outcome::Failure((
+                        Status::Forbidden,
+                        "Api key lacks the required role.",
+                    )),
+                    Outcome::Failure(e) => Outcome::Failure(e),
+                    Outcome::Forward(f) => Outcome::Forward(f),
+                }
+            }
+        }
+
+        impl<'a> OpenApiFromRequest<'a> for $name {
+            fn from_request_input(
+                _gen: &mut OpenApiGenerator,
+                _name: String,
+                _required: bool,
+            ) -> rocket_okapi::Result<RequestHeaderInput> {
+                Ok(security_input(Some($role)))
+            }
+
+            fn get_responses(
+                gen: &mut OpenApiGenerator,
+            ) -> rocket_okapi::Result<Responses> {
+                use rocket_okapi::okapi::openapi3::RefOr;
+                let mut responses = ApiKey::get_responses(gen)?;
+                responses.responses.insert(
+                    "403".to_owned(),
+                    RefOr::Object(crate::common::forbidden_response(gen)),
+                );
+                Ok(responses)
+            }
+        }
+    };
+}
+
+role_guard!(
+    /// A key with the mapper role.
+    Mapper,
+    Role::Mapper
+);
+role_guard!(
+    /// A key with the tester role.
+    Tester,
+    Role::Tester
+);
+role_guard!(
+    /// A key with the admin role.
+    Admin,
+    Role::Admin
+);
@@ -0,0 +1,133 @@
+//! A map is only approved once enough testers approved it independently.
+//! Every API key has one vote per map file, so uploading a new version of a
+//! map starts a new vote. The votes of a published map are for its
+//! candidate, if it has one. A single decline blocks the approval until its
+//! reviewer approves the map as well.
+
+use crate::{Map, CONFIG};
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::iter;
+use structsy::{OwnedSytx, Ref, SRes, Structsy, StructsyTx};
+use structsy_derive::{queries, Persistent, PersistentEmbedded};
+
+#[derive(PersistentEmbedded, Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Approve,
+    Decline,
+}
+
+#[derive(Persistent, Debug)]
+pub struct ApprovalVote {
+    #[index]
+    pub map: String,
+    /// The identity of the API key which voted.
+    pub reviewer: String,
+    pub verdict: Verdict,
+    /// The map file the vote is for.
+    pub sha256: Option<String>,
+    pub created_at: u64,
+}
+
+#[queries(ApprovalVote)]
+pub trait ApprovalVoteByMap {
+    fn by_map(self, map: &str) -> Self;
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Tally {
+    pub approvals: usize,
+    pub declines: usize,
+    /// How many approvals it takes to approve the map.
+    pub required: usize,
+}
+
+impl Tally {
+    fn of<'a>(votes: impl IntoIterator<Item = &'a ApprovalVote>) -> Self {
+        let mut tally = Tally {
+            approvals: 0,
+            declines: 0,
+            required: CONFIG.approval_quorum,
+        };
+        for vote in votes {
+            match vote.verdict {
+                Verdict::Approve => tally.approvals += 1,
+                Verdict::Decline => tally.declines += 1,
+            }
+        }
+        tally
+    }
+
+    /// Whether the votes are enough to approve the map.
+    pub fn reached(&self) -> bool {
+        self.approvals >= self.required && self.declines == 0
+    }
+}
+
+/// All votes of the given map together with their ids, including the ones
+/// for earlier versions of it.
+pub fn of_map(
+    db: &Structsy,
+    map: &str,
+) -> Vec<(Ref<ApprovalVote>, ApprovalVote)> {
+    db.query::<ApprovalVote>().by_map(map).into_iter().collect()
+}
+
+/// The votes for the tested file of the map.
+pub fn tally(db: &Structsy, map: &Map) -> Tally {
+    Tally::of(
+        of_map(db, &map.name)
+            .iter()
+            .map(|(_id, vote)| vote)
+            .filter(|vote| vote.sha256.as_ref() == map.tested_sha256()),
+    )
+}
+
+/// Records the vote in the transaction, replacing the earlier vote of the
+/// reviewer, and returns the votes for the tested file of the map.
+pub fn record(
+    db: &Structsy,
+    tx: &mut OwnedSytx,
+    map: &Map,
+    vote: &ApprovalVote,
+) -> SRes<Tally> {
+    let votes = of_map(db, &map.name);
+    match votes.iter().find(|(_id, v)| v.reviewer == vote.reviewer) {
+        Some((id, _earlier)) => tx.update(id, vote)?,
+        None => {
+            tx.insert(vote)?;
+        }
+    }
+    Ok(Tally::of(
+        votes
+            .iter()
+            .map(|(_id, v)| v)
+            .filter(|v| {
+                v.reviewer != vote.reviewer
+                    && v.sha256.as_ref() == map.tested_sha256()
+            })
+            .chain(iter::once(vote)),
+    ))
+}
+
+/// The votes for the tested files of all maps, by map name.
+pub fn tallies(db: &Structsy, maps: &[Map]) -> HashMap<String, Tally> {
+    let tested = maps
+        .iter()
+        .map(|map| (map.name.as_str(), map.tested_sha256()))
+        .collect::<HashMap<_, _>>();
+    let mut by_map = HashMap::<String, Vec<ApprovalVote>>::new();
+    for (_id, vote) in db.query::<ApprovalVote>().into_iter() {
+        if tested.get(vote.map.as_str()) == Some(&vote.sha256.as_ref()) {
+            by_map.entry(vote.map.clone()).or_default().push(vote);
+        }
+    }
+    maps.iter()
+        .map(|map| {
+            let votes = by_map.remove(&map.name).unwrap_or_default();
+            (map.name.clone(), Tally::of(&votes))
+        })
+        .collect()
+}
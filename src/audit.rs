@@ -0,0 +1,263 @@
+//! An append-only log of every change, recorded in the same transaction as
+//! the change itself. Events are numbered in the order they happen, as many
+//! happen within the same second. Every request gets an id, which is sent
+//! back in the `X-Request-Id` header and stored with the events of the
+//! request. Clients may send their own id in the same header.
+
+use crate::apikey::ApiKey;
+use crate::{get_current_time, Difficulty, Map, MapState};
+use rand_core::{OsRng, RngCore};
+use rocket::fairing::AdHoc;
+use rocket::serde::Serialize;
+use rocket::{FromForm, FromFormField, Request};
+use schemars::JsonSchema;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use structsy::Structsy;
+use structsy_derive::{Persistent, PersistentEmbedded};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longer ids sent by clients are replaced by our own.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// The number of the next event, see [`init`].
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(
+    Serialize,
+    JsonSchema,
+    FromFormField,
+    PersistentEmbedded,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[schemars(rename_all = "snake_case")]
+pub enum AuditAction {
+    Upload,
+    Import,
+    #[field(value = "change_difficulty")]
+    ChangeDifficulty,
+    #[field(value = "change_tags")]
+    ChangeTags,
+    Delete,
+    Rename,
+    Approve,
+    Decline,
+    Publish,
+    Recall,
+    Archive,
+    Review,
+    Rollback,
+    #[field(value = "create_key")]
+    CreateKey,
+    #[field(value = "revoke_key")]
+    RevokeKey,
+    #[field(value = "expire_key")]
+    ExpireKey,
+}
+
+#[derive(Serialize, JsonSchema, Persistent, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditEvent {
+    /// Orders the events, later events have larger numbers.
+    pub sequence: u64,
+    /// The label of the API key, or the command which made the change.
+    pub actor: String,
+    pub action: AuditAction,
+    pub map: Option<String>,
+    /// The name of a renamed map before the rename.
+    pub previous_name: Option<String>,
+    pub previous_state: Option<MapState>,
+    pub new_state: Option<MapState>,
+    pub previous_difficulty: Option<Difficulty>,
+    pub new_difficulty: Option<Difficulty>,
+    pub details: Option<String>,
+    pub created_at: u64,
+    /// `None` for changes outside of a request.
+    pub request_id: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(actor: &str, action: AuditAction) -> Self {
+        AuditEvent {
+            sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::SeqCst),
+            actor: actor.to_owned(),
+            action,
+            map: None,
+            previous_name: None,
+            previous_state: None,
+            new_state: None,
+            previous_difficulty: None,
+            new_difficulty: None,
+            details: None,
+            // the log is ordered by the sequence, so a broken clock is no
+            // reason to fail the change
+            created_at: get_current_time().unwrap_or_default(),
+            request_id: None,
+        }
+    }
+
+    /// An event caused by the request the key was sent with.
+    pub fn by(key: &ApiKey, action: AuditAction) -> Self {
+        AuditEvent {
+            request_id: Some(key.request_id().to_owned()),
+            ..AuditEvent::new(key.label(), action)
+        }
+    }
+
+    /// The map as it was before the change.
+    pub fn before(self, map: &Map) -> Self {
+        AuditEvent {
+            map: Some(map.name.clone()),
+            previous_state: Some(map.state),
+            previous_difficulty: Some(map.difficulty),
+            ..self
+        }
+    }
+
+    /// The map as it is after the change.
+    pub fn after(self, map: &Map) -> Self {
+        AuditEvent {
+            map: Some(map.name.clone()),
+            new_state: Some(map.state),
+            new_difficulty: Some(map.difficulty),
+            ..self
+        }
+    }
+
+    pub fn renamed_from(self, name: &str) -> Self {
+        AuditEvent {
+            previous_name: Some(name.to_owned()),
+            ..self
+        }
+    }
+
+    pub fn details(self, details: String) -> Self {
+        AuditEvent {
+            details: Some(details),
+            ..self
+        }
+    }
+}
+
+#[derive(FromForm, JsonSchema)]
+pub struct AuditFilter {
+    map: Option<String>,
+    actor: Option<String>,
+    action: Option<AuditAction>,
+    /// Only events at or after this unix timestamp.
+    since: Option<u64>,
+    pub offset: Option<usize>,
+    /// 100 if not given and 1000 at most.
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.map
+            .as_ref()
+            .is_none_or(|map| event.map.as_ref() == Some(&map.to_lowercase()))
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|actor| &event.actor == actor)
+            && self.action.is_none_or(|action| event.action == action)
+            && self.since.is_none_or(|since| event.created_at >= since)
+    }
+}
+
+/// Continues the numbering after the events already in the database. Has to
+/// run before any event is recorded.
+pub fn init(db: &Structsy) {
+    let next = db
+        .query::<AuditEvent>()
+        .into_iter()
+        .map(|(_id, event)| event.sequence + 1)
+        .max()
+        .unwrap_or(0);
+    NEXT_SEQUENCE.fetch_max(next, Ordering::SeqCst);
+}
+
+/// All events, the oldest first.
+fn events(db: &Structsy) -> Vec<AuditEvent> {
+    let mut events = db
+        .query::<AuditEvent>()
+        .into_iter()
+        .map(|(_id, event)| event)
+        .collect::<Vec<_>>();
+    events.sort_by_key(|event| event.sequence);
+    events
+}
+
+/// The matching events, the oldest first.
+pub fn query(db: &Structsy, filter: &AuditFilter) -> Vec<AuditEvent> {
+    events(db)
+        .into_iter()
+        .filter(|event| filter.matches(event))
+        .collect()
+}
+
+/// The events of the map, including the ones from before it was renamed,
+/// the oldest first. A map deleted before the current one was created under
+/// the same name is not part of it.
+pub fn timeline(db: &Structsy, name: &str) -> Vec<AuditEvent> {
+    let mut names = HashSet::new();
+    names.insert(name.to_owned());
+    let mut timeline = Vec::new();
+    for event in events(db).into_iter().rev() {
+        let map = match &event.map {
+            Some(map) if names.contains(map) => map.clone(),
+            _ => continue,
+        };
+        if event.action == AuditAction::Delete {
+            names.remove(&map);
+            continue;
+        }
+        if let Some(previous_name) = &event.previous_name {
+            names.remove(&map);
+            names.insert(previous_name.clone());
+        }
+        timeline.push(event);
+    }
+    timeline.reverse();
+    timeline
+}
+
+struct RequestId(String);
+
+/// The id of the request, the one sent by the client if it is usable.
+pub fn request_id<'r>(request: &'r Request<'_>) -> &'r str {
+    &request
+        .local_cache(|| {
+            let sent = request
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .map(str::trim)
+                .filter(|id| {
+                    !id.is_empty()
+                        && id.len() <= MAX_REQUEST_ID_LEN
+                        && id.chars().all(|c| c.is_ascii_graphic())
+                });
+            RequestId(sent.map(ToOwned::to_owned).unwrap_or_else(|| {
+                let mut bytes = [0u8; 16];
+                OsRng.fill_bytes(&mut bytes);
+                bytes.iter().map(|b| format!("{:02x}", b)).collect()
+            }))
+        })
+        .0
+}
+
+/// Sends the id of every request back in the `X-Request-Id` header.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_response("Request id", |request, response| {
+        Box::pin(async move {
+            response.set_raw_header(
+                REQUEST_ID_HEADER,
+                request_id(request).to_owned(),
+            );
+        })
+    })
+}
@@ -1,3 +1,6 @@
+use rocket::fs::NamedFile;
+use rocket::http::ContentType;
+use rocket::serde::json::Json;
 use rocket::Request;
 use rocket::{catch, response, response::Responder, Response};
 use rocket_okapi::gen::OpenApiGenerator;
@@ -45,6 +48,18 @@ pub fn unauthorized() -> MyError {
     }
 }
 
+#[catch(403)]
+pub fn forbidden() -> MyError {
+    MyError {
+        err: "Forbidden".to_owned(),
+        msg: Some(
+            "The API key given lacks the role required for this request."
+                .to_owned(),
+        ),
+        http_status_code: 403,
+    }
+}
+
 /// Create my custom response
 ///
 /// Putting this in a separate function somewhere will resolve issues like
@@ -89,6 +104,26 @@ pub fn unauthorized_response(
     }
 }
 
+pub fn forbidden_response(
+    gen: &mut OpenApiGenerator,
+) -> okapi::openapi3::Response {
+    let schema = gen.json_schema::<MyError>();
+    okapi::openapi3::Response {
+        description: "\
+        # 403 Forbidden\n\
+        The API key given lacks the role required for this request. \
+        "
+        .to_owned(),
+        content: okapi::map! {
+            "application/json".to_owned() => MediaType {
+                schema: Some(schema),
+                ..Default::default()
+            }
+        },
+        ..Default::default()
+    }
+}
+
 impl<'r> Responder<'r, 'static> for MyError {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
         // Convert object to json
@@ -115,3 +150,102 @@ impl OpenApiResponderInner for MyError {
         })
     }
 }
+
+/// The number of items on a page if no `limit` is given.
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// Larger `limit`s are lowered to this.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// A page of a listing, with the number of all matching items in the
+/// `X-Total-Count` header.
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+}
+
+impl<T> Paginated<T> {
+    /// The items after the first `offset` ones, at most `limit` of them.
+    pub fn page(
+        items: Vec<T>,
+        offset: Option<usize>,
+        limit: Option<usize>,
+    ) -> Self {
+        let total = items.len();
+        let items = items
+            .into_iter()
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE))
+            .collect();
+        Paginated { items, total }
+    }
+}
+
+impl<'r, T: serde::Serialize> Responder<'r, 'static> for Paginated<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(Json(self.items).respond_to(req)?)
+            .raw_header("X-Total-Count", self.total.to_string())
+            .ok()
+    }
+}
+
+impl<T: serde::Serialize + schemars::JsonSchema + Send> OpenApiResponderInner
+    for Paginated<T>
+{
+    fn responses(
+        gen: &mut OpenApiGenerator,
+    ) -> Result<Responses, OpenApiError> {
+        use okapi::openapi3::{Header, ParameterValue, RefOr};
+        let mut responses = Json::<Vec<T>>::responses(gen)?;
+        if let Some(RefOr::Object(response)) =
+            responses.responses.get_mut("200")
+        {
+            let header = Header {
+                description: Some(
+                    "The number of all matching items.".to_owned(),
+                ),
+                required: true,
+                deprecated: false,
+                allow_empty_value: false,
+                value: ParameterValue::Schema {
+                    style: None,
+                    explode: None,
+                    allow_reserved: false,
+                    schema: gen.json_schema_no_ref::<usize>(),
+                    example: None,
+                    examples: None,
+                },
+                extensions: Default::default(),
+            };
+            response
+                .headers
+                .insert("X-Total-Count".to_owned(), RefOr::Object(header));
+        }
+        Ok(responses)
+    }
+}
+
+/// A map file, downloaded under the name of the map.
+pub struct MapFile {
+    pub file: NamedFile,
+    pub name: String,
+}
+
+impl<'r> Responder<'r, 'static> for MapFile {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(self.file.respond_to(req)?)
+            .header(ContentType::Binary)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.map\"", self.name),
+            )
+            .ok()
+    }
+}
+
+impl OpenApiResponderInner for MapFile {
+    fn responses(
+        gen: &mut OpenApiGenerator,
+    ) -> Result<Responses, OpenApiError> {
+        NamedFile::responses(gen)
+    }
+}
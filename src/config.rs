@@ -1,8 +1,28 @@
-use std::path::PathBuf;
+use crate::keys::KeyStore;
+use crate::targets::Target;
+use rocket::data::ByteUnit;
+use std::{path::PathBuf, time::Duration};
 
 pub struct Config {
-    pub apikeys: Vec<String>,
+    pub apikeys: KeyStore,
     pub test_map_folder: PathBuf,
     pub public_map_folder: PathBuf,
+    pub preview_folder: PathBuf,
+    pub revision_folder: PathBuf,
+    pub declined_retention: Duration,
+    pub purge_interval: Duration,
+    pub max_upload_size: ByteUnit,
+    pub download: DownloadConfig,
+    pub targets: Vec<Target>,
+    pub approval_quorum: usize,
     pub dev: bool,
 }
+
+pub struct DownloadConfig {
+    pub timeout: Duration,
+    pub max_size: ByteUnit,
+    pub retries: u32,
+    pub allowed_schemes: Vec<String>,
+    pub allowed_hosts: Vec<String>,
+    pub allow_private_hosts: bool,
+}
@@ -0,0 +1,484 @@
+//! A reader for the teeworlds datafile format, which is used by `.map` files.
+//!
+//! The layout is described in the teeworlds source (`engine/shared/datafile.cpp`):
+//! a fixed header, the item types, the item and data offsets, the items and
+//! finally the (zlib compressed since version 4) data blocks.
+
+use flate2::read::ZlibDecoder;
+use std::convert::TryInto;
+use std::io::Read;
+
+/// Size of the fixed datafile header in bytes.
+const HEADER_SIZE: usize = 36;
+
+/// Item type of the map info item, see `MAPITEMTYPE_INFO` in teeworlds.
+pub const ITEMTYPE_INFO: u16 = 1;
+
+/// Upper bound for a single decompressed data block, to not let a malicious
+/// file exhaust our memory.
+const MAX_DATA_SIZE: usize = 64 * 1024 * 1024;
+
+/// Upper bound for all decompressed data blocks together, as many small
+/// blocks can exhaust our memory as well.
+const MAX_TOTAL_DATA_SIZE: usize = 128 * 1024 * 1024;
+
+#[derive(Debug, derive_more::Display)]
+pub enum DatafileError {
+    #[display(fmt = "the file is too small to be a map ({} bytes)", _0)]
+    TooSmall(usize),
+    #[display(fmt = "invalid magic bytes, expected DATA or ATAD")]
+    InvalidMagic,
+    #[display(fmt = "unsupported datafile version {}, expected 3 or 4", _0)]
+    UnsupportedVersion(i32),
+    #[display(fmt = "invalid header: {}", _0)]
+    InvalidHeader(&'static str),
+    #[display(
+        fmt = "the file is truncated, expected {} bytes but got {}",
+        expected,
+        actual
+    )]
+    Truncated { expected: usize, actual: usize },
+    #[display(fmt = "item type {} points outside of the item list", _0)]
+    InvalidItemType(usize),
+    #[display(fmt = "item {} is out of bounds or malformed", _0)]
+    InvalidItem(usize),
+    #[display(fmt = "data block {} is out of bounds", _0)]
+    InvalidDataOffset(usize),
+    #[display(fmt = "the data blocks decompress to more than {} bytes", _0)]
+    DataTooLarge(usize),
+    #[display(fmt = "data block {} could not be decompressed: {}", _0, _1)]
+    Decompression(usize, String),
+    #[display(
+        fmt = "data block {} has size {} but should be {}",
+        index,
+        actual,
+        expected
+    )]
+    DataSizeMismatch {
+        index: usize,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl std::error::Error for DatafileError {}
+
+/// A single item of a datafile.
+pub struct Item {
+    pub type_id: u16,
+    pub id: u16,
+    pub data: Vec<i32>,
+}
+
+/// The metadata stored in the map info item of a map.
+#[derive(Default)]
+pub struct MapInfo {
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub credits: Option<String>,
+    pub license: Option<String>,
+}
+
+/// A fully parsed and validated datafile.
+pub struct Datafile {
+    version: i32,
+    items: Vec<Item>,
+    data: Vec<Vec<u8>>,
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_i32s(bytes: &[u8], offset: usize, count: usize) -> Vec<i32> {
+    (0..count)
+        .map(|i| read_i32(bytes, offset + i * 4))
+        .collect()
+}
+
+fn to_size(value: i32, what: &'static str) -> Result<usize, DatafileError> {
+    value
+        .try_into()
+        .map_err(|_| DatafileError::InvalidHeader(what))
+}
+
+impl Datafile {
+    /// Parses the given bytes, checking the header, all item and data
+    /// offsets and decompressing every data block.
+    pub fn parse(bytes: &[u8]) -> Result<Datafile, DatafileError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(DatafileError::TooSmall(bytes.len()));
+        }
+        if &bytes[0..4] != b"DATA" && &bytes[0..4] != b"ATAD" {
+            return Err(DatafileError::InvalidMagic);
+        }
+        let version = read_i32(bytes, 4);
+        if version != 3 && version != 4 {
+            return Err(DatafileError::UnsupportedVersion(version));
+        }
+        let num_item_types = to_size(read_i32(bytes, 16), "num_item_types")?;
+        let num_items = to_size(read_i32(bytes, 20), "num_items")?;
+        let num_data = to_size(read_i32(bytes, 24), "num_data")?;
+        let item_size = to_size(read_i32(bytes, 28), "item_size")?;
+        let data_size = to_size(read_i32(bytes, 32), "data_size")?;
+
+        let item_types_start = HEADER_SIZE;
+        let item_offsets_start = item_types_start + num_item_types * 12;
+        let data_offsets_start = item_offsets_start + num_items * 4;
+        let data_sizes_start = data_offsets_start + num_data * 4;
+        let items_start = if version == 4 {
+            data_sizes_start + num_data * 4
+        } else {
+            data_sizes_start
+        };
+        let data_start = items_start + item_size;
+        let expected = data_start + data_size;
+        if bytes.len() < expected {
+            return Err(DatafileError::Truncated {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+
+        let item_offsets = read_i32s(bytes, item_offsets_start, num_items);
+        let items = (0..num_items)
+            .map(|i| {
+                let invalid = || DatafileError::InvalidItem(i);
+                let start: usize =
+                    item_offsets[i].try_into().map_err(|_| invalid())?;
+                let end = match item_offsets.get(i + 1) {
+                    Some(&next) => next.try_into().map_err(|_| invalid())?,
+                    None => item_size,
+                };
+                if start + 8 > end || end > item_size {
+                    return Err(invalid());
+                }
+                let type_and_id = read_i32(bytes, items_start + start);
+                let size: usize = read_i32(bytes, items_start + start + 4)
+                    .try_into()
+                    .map_err(|_| invalid())?;
+                if !size.is_multiple_of(4) || start + 8 + size > end {
+                    return Err(invalid());
+                }
+                Ok(Item {
+                    type_id: ((type_and_id >> 16) & 0xffff) as u16,
+                    id: (type_and_id & 0xffff) as u16,
+                    data: read_i32s(bytes, items_start + start + 8, size / 4),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut seen = std::collections::HashSet::new();
+        for (i, item) in items.iter().enumerate() {
+            if !seen.insert((item.type_id, item.id)) {
+                return Err(DatafileError::InvalidItem(i));
+            }
+        }
+
+        for i in 0..num_item_types {
+            let offset = item_types_start + i * 12;
+            let type_id = read_i32(bytes, offset);
+            let start =
+                to_size(read_i32(bytes, offset + 4), "item type start")?;
+            let num = to_size(read_i32(bytes, offset + 8), "item type count")?;
+            let in_bounds = start + num <= items.len()
+                && items[start..start + num]
+                    .iter()
+                    .all(|item| i32::from(item.type_id) == type_id);
+            if !in_bounds {
+                return Err(DatafileError::InvalidItemType(i));
+            }
+        }
+
+        let data_offsets = read_i32s(bytes, data_offsets_start, num_data);
+        // the sizes are checked before anything is decompressed, every block
+        // has to decompress to exactly its size
+        let data_sizes = if version == 4 {
+            read_i32s(bytes, data_sizes_start, num_data)
+                .into_iter()
+                .map(|size| to_size(size, "uncompressed data size"))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+        if data_sizes.iter().any(|&size| size > MAX_DATA_SIZE) {
+            return Err(DatafileError::InvalidHeader("uncompressed data size"));
+        }
+        if data_sizes.iter().sum::<usize>() > MAX_TOTAL_DATA_SIZE {
+            return Err(DatafileError::DataTooLarge(MAX_TOTAL_DATA_SIZE));
+        }
+        let data = (0..num_data)
+            .map(|i| {
+                let invalid = || DatafileError::InvalidDataOffset(i);
+                let start: usize =
+                    data_offsets[i].try_into().map_err(|_| invalid())?;
+                let end = match data_offsets.get(i + 1) {
+                    Some(&next) => next.try_into().map_err(|_| invalid())?,
+                    None => data_size,
+                };
+                if start > end || end > data_size {
+                    return Err(invalid());
+                }
+                let raw = &bytes[data_start + start..data_start + end];
+                if version == 3 {
+                    return Ok(raw.to_vec());
+                }
+                let expected = data_sizes[i];
+                let mut decompressed = Vec::with_capacity(expected);
+                ZlibDecoder::new(raw)
+                    .take(expected as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| {
+                        DatafileError::Decompression(i, e.to_string())
+                    })?;
+                if decompressed.len() != expected {
+                    return Err(DatafileError::DataSizeMismatch {
+                        index: i,
+                        expected,
+                        actual: decompressed.len(),
+                    });
+                }
+                Ok(decompressed)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Datafile {
+            version,
+            items,
+            data,
+        })
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn data(&self) -> &[Vec<u8>] {
+        &self.data
+    }
+
+    pub fn find_item(&self, type_id: u16, id: u16) -> Option<&Item> {
+        self.items
+            .iter()
+            .find(|item| item.type_id == type_id && item.id == id)
+    }
+
+    /// Reads the zero terminated string stored in the given data block.
+    /// Negative indices are used by teeworlds to signal a missing string.
+    pub fn string(&self, index: i32) -> Option<String> {
+        let index: usize = index.try_into().ok()?;
+        let data = self.data.get(index)?;
+        let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        let string = String::from_utf8_lossy(&data[..end]).into_owned();
+        if string.is_empty() {
+            None
+        } else {
+            Some(string)
+        }
+    }
+
+    /// Extracts the author, version, credits and license of the map. Missing
+    /// fields (or a missing info item) are returned as `None`.
+    pub fn map_info(&self) -> MapInfo {
+        let item = match self.find_item(ITEMTYPE_INFO, 0) {
+            Some(item) => item,
+            None => return MapInfo::default(),
+        };
+        let field =
+            |i: usize| item.data.get(i).and_then(|&index| self.string(index));
+        MapInfo {
+            author: field(1),
+            version: field(2),
+            credits: field(3),
+            license: field(4),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// A version 4 datafile with an info item pointing to four strings.
+    fn map_file() -> Vec<u8> {
+        let strings = ["alice", "1.0", "thanks", "CC-BY"];
+        let data = strings
+            .iter()
+            .map(|s| format!("{}\0", s).into_bytes())
+            .collect::<Vec<_>>();
+        let info = [1, 0, 1, 2, 3, -1];
+        build(&[(ITEMTYPE_INFO, 0, &info)], &data)
+    }
+
+    /// A version 4 datafile of the given items and uncompressed data.
+    pub fn build(items: &[(u16, u16, &[i32])], data: &[Vec<u8>]) -> Vec<u8> {
+        let ints = |values: &[i32]| {
+            values
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        let mut item_offsets = Vec::new();
+        let mut item_bytes = Vec::new();
+        for (type_id, id, values) in items {
+            item_offsets.push(item_bytes.len() as i32);
+            let type_and_id = (i32::from(*type_id) << 16) | i32::from(*id);
+            item_bytes.extend(ints(&[type_and_id, values.len() as i32 * 4]));
+            item_bytes.extend(ints(values));
+        }
+        let mut data_offsets = Vec::new();
+        let mut data_bytes = Vec::new();
+        for block in data {
+            data_offsets.push(data_bytes.len() as i32);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(block).unwrap();
+            data_bytes.extend(encoder.finish().unwrap());
+        }
+        let sizes = data.iter().map(|d| d.len() as i32).collect::<Vec<_>>();
+
+        let mut types = Vec::<[i32; 3]>::new();
+        for (i, (type_id, _, _)) in items.iter().enumerate() {
+            match types.last_mut() {
+                Some([t, _, n]) if *t == i32::from(*type_id) => *n += 1,
+                _ => types.push([i32::from(*type_id), i as i32, 1]),
+            }
+        }
+
+        let mut file = b"DATA".to_vec();
+        file.extend(ints(&[
+            4,
+            0,
+            0,
+            types.len() as i32,
+            items.len() as i32,
+            data.len() as i32,
+            item_bytes.len() as i32,
+            data_bytes.len() as i32,
+        ]));
+        file.extend(ints(&types.concat()));
+        file.extend(ints(&item_offsets));
+        file.extend(ints(&data_offsets));
+        file.extend(ints(&sizes));
+        file.extend(item_bytes);
+        file.extend(data_bytes);
+        file
+    }
+
+    fn set_i32(file: &mut [u8], offset: usize, value: i32) {
+        file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Where the data offsets and the data sizes of [`map_file`] start.
+    const DATA_OFFSETS: usize = HEADER_SIZE + 12 + 4;
+    const DATA_SIZES: usize = DATA_OFFSETS + 4 * 4;
+
+    #[test]
+    fn reads_the_map_info() {
+        let datafile = Datafile::parse(&map_file()).unwrap();
+        assert_eq!(datafile.version(), 4);
+        assert_eq!(datafile.items().len(), 1);
+        assert_eq!(datafile.data().len(), 4);
+        let info = datafile.map_info();
+        assert_eq!(info.author.as_deref(), Some("alice"));
+        assert_eq!(info.version.as_deref(), Some("1.0"));
+        assert_eq!(info.credits.as_deref(), Some("thanks"));
+        assert_eq!(info.license.as_deref(), Some("CC-BY"));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut file = map_file();
+        file[0..4].copy_from_slice(b"NOPE");
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_versions() {
+        let mut file = map_file();
+        set_i32(&mut file, 4, 5);
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::UnsupportedVersion(5))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        assert!(matches!(
+            Datafile::parse(b"DATA"),
+            Err(DatafileError::TooSmall(4))
+        ));
+        let file = map_file();
+        assert!(matches!(
+            Datafile::parse(&file[..file.len() - 1]),
+            Err(DatafileError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_offsets() {
+        let mut file = map_file();
+        set_i32(&mut file, HEADER_SIZE + 12, 1000);
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::InvalidItem(0))
+        ));
+
+        let mut file = map_file();
+        set_i32(&mut file, DATA_OFFSETS + 3 * 4, 1000);
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::InvalidDataOffset(2))
+        ));
+
+        let mut file = map_file();
+        set_i32(&mut file, DATA_OFFSETS, -1);
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::InvalidDataOffset(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_size_mismatches() {
+        let mut file = map_file();
+        set_i32(&mut file, DATA_SIZES + 4, 100);
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::DataSizeMismatch {
+                index: 1,
+                expected: 100,
+                actual: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn limits_the_total_data_size() {
+        let mut file = map_file();
+        for i in 0..4 {
+            set_i32(&mut file, DATA_SIZES + i * 4, MAX_DATA_SIZE as i32);
+        }
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::DataTooLarge(MAX_TOTAL_DATA_SIZE))
+        ));
+
+        let mut file = map_file();
+        set_i32(&mut file, DATA_SIZES, MAX_DATA_SIZE as i32 + 1);
+        assert!(matches!(
+            Datafile::parse(&file),
+            Err(DatafileError::InvalidHeader("uncompressed data size"))
+        ));
+    }
+}
@@ -0,0 +1,316 @@
+//! Downloads map files from user supplied URLs.
+//!
+//! As the URL comes straight from the API, every download is bounded in time
+//! and size, only goes to allowed schemes and hosts and never to loopback or
+//! private addresses, so `/create` can't be used to reach our internal
+//! network. Redirects are followed by hand, so every hop gets the same checks.
+
+use crate::CONFIG;
+use reqwest::{header, redirect::Policy, Client, StatusCode, Url};
+use rocket::tokio::{net::lookup_host, time::sleep};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, derive_more::Display)]
+pub enum DownloadError {
+    #[display(fmt = "invalid url: {}", _0)]
+    InvalidUrl(String),
+    #[display(fmt = "the scheme \"{}\" is not allowed", _0)]
+    SchemeNotAllowed(String),
+    #[display(fmt = "the host \"{}\" is not allowed", _0)]
+    HostNotAllowed(String),
+    #[display(fmt = "the host \"{}\" could not be resolved", _0)]
+    UnresolvableHost(String),
+    #[display(fmt = "too many redirects")]
+    TooManyRedirects,
+    #[display(fmt = "the server responded with {}", _0)]
+    Status(StatusCode),
+    #[display(fmt = "the server sent {} instead of a map file", _0)]
+    ContentType(String),
+    #[display(fmt = "the file is larger than {} bytes", _0)]
+    TooLarge(u64),
+    #[display(fmt = "{}", _0)]
+    Request(reqwest::Error),
+}
+
+impl std::error::Error for DownloadError {}
+
+impl DownloadError {
+    /// Whether trying again might succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Status(status) => status.is_server_error(),
+            DownloadError::Request(e) => !e.is_builder(),
+            DownloadError::UnresolvableHost(_) => true,
+            _ => false,
+        }
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "this network" and the shared address space used by carrier NAT
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        // protocol assignments, benchmarking and the reserved addresses
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240)
+}
+
+/// The IPv4 address the IPv6 address leads to, if it embeds one.
+fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let to_ipv4 = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+    match ip.segments() {
+        // IPv4 mapped and IPv4 compatible
+        [0, 0, 0, 0, 0, 0xffff | 0, high, low]
+        // NAT64
+        | [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(to_ipv4(high, low)),
+        // 6to4
+        [0x2002, high, low, ..] => Some(to_ipv4(high, low)),
+        _ => None,
+    }
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    // the unspecified and the loopback address look IPv4 compatible
+    if ip.is_unspecified() || ip.is_loopback() {
+        return false;
+    }
+    if let Some(ip) = embedded_ipv4(ip) {
+        return is_public_ipv4(&ip);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_multicast()
+        // unique local addresses
+        || (first & 0xfe00) == 0xfc00
+        // link local addresses
+        || (first & 0xffc0) == 0xfe80)
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+/// Whether the lowercase host matches the allow list. An entry like
+/// `*.example.com` allows all subdomains, an empty list allows every host.
+fn is_allowed_host(host: &str, hosts: &[String]) -> bool {
+    hosts.is_empty()
+        || hosts.iter().any(|allowed| {
+            let allowed = allowed.to_lowercase();
+            match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => host == allowed,
+            }
+        })
+}
+
+/// Checks the url and resolves its host to an address we are allowed to
+/// connect to.
+async fn check_url(url: &Url) -> Result<SocketAddr, DownloadError> {
+    let config = &CONFIG.download;
+    if !config.allowed_schemes.iter().any(|s| s == url.scheme()) {
+        return Err(DownloadError::SchemeNotAllowed(url.scheme().to_owned()));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| DownloadError::InvalidUrl(url.to_string()))?
+        .to_lowercase();
+    if !is_allowed_host(&host, &config.allowed_hosts) {
+        return Err(DownloadError::HostNotAllowed(host));
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| DownloadError::InvalidUrl(url.to_string()))?;
+    let lookup = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = lookup_host((lookup, port))
+        .await
+        .map_err(|_| DownloadError::UnresolvableHost(host.clone()))?
+        .collect::<Vec<_>>();
+    if !config.allow_private_hosts
+        && addrs.iter().any(|addr| !is_public(&addr.ip()))
+    {
+        return Err(DownloadError::HostNotAllowed(host));
+    }
+    addrs
+        .into_iter()
+        .next()
+        .ok_or(DownloadError::UnresolvableHost(host))
+}
+
+async fn try_download(url: &str) -> Result<Vec<u8>, DownloadError> {
+    let config = &CONFIG.download;
+    let max_size = config.max_size.as_u64();
+    let mut url = Url::parse(url)
+        .map_err(|_| DownloadError::InvalidUrl(url.to_owned()))?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = check_url(&url).await?;
+        // pin the connection to the address we checked, so the host can't
+        // resolve to a different one in between, and never go through a
+        // proxy, which would connect wherever it resolves the host to
+        let mut builder = Client::builder()
+            .redirect(Policy::none())
+            .no_proxy()
+            .timeout(config.timeout);
+        if let Some(domain) = url.domain() {
+            builder = builder.resolve(domain, addr);
+        }
+        let client = builder.build().map_err(DownloadError::Request)?;
+        let mut response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(DownloadError::Request)?;
+
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or(DownloadError::Status(status))?;
+            url = url
+                .join(location)
+                .map_err(|_| DownloadError::InvalidUrl(location.to_owned()))?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(DownloadError::Status(status));
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default();
+        if content_type.starts_with("text/") {
+            return Err(DownloadError::ContentType(content_type.to_owned()));
+        }
+        if response.content_length().unwrap_or(0) > max_size {
+            return Err(DownloadError::TooLarge(max_size));
+        }
+
+        let mut file = Vec::new();
+        while let Some(chunk) =
+            response.chunk().await.map_err(DownloadError::Request)?
+        {
+            if (file.len() + chunk.len()) as u64 > max_size {
+                return Err(DownloadError::TooLarge(max_size));
+            }
+            file.extend_from_slice(&chunk);
+        }
+        return Ok(file);
+    }
+
+    Err(DownloadError::TooManyRedirects)
+}
+
+/// Downloads the file at the given url, retrying transient failures.
+pub async fn download(url: &str) -> Result<Vec<u8>, DownloadError> {
+    let mut attempt = 0;
+    loop {
+        match try_download(url).await {
+            Err(e) if e.is_transient() && attempt < CONFIG.download.retries => {
+                attempt += 1;
+                eprintln!(
+                    "Downloading \"{}\" failed ({}), retrying ({}/{})",
+                    url, e, attempt, CONFIG.download.retries
+                );
+                sleep(Duration::from_millis(500 * u64::from(attempt))).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_internal_ipv4_addresses() {
+        for ip in [
+            "0.1.2.3",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.0.0.8",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "100.128.0.1", "192.0.3.1", "198.20.0.1"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_internal_ipv6_addresses() {
+        for ip in [
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:c0a8:101::1",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+        for ip in [
+            "2606:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+        ] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn matches_allowed_hosts() {
+        let hosts =
+            vec!["Maps.Example.com".to_owned(), "*.DDNet.org".to_owned()];
+        assert!(is_allowed_host("maps.example.com", &hosts));
+        assert!(is_allowed_host("dl.ddnet.org", &hosts));
+        assert!(is_allowed_host("a.b.ddnet.org", &hosts));
+        assert!(!is_allowed_host("ddnet.org", &hosts));
+        assert!(!is_allowed_host("evilddnet.org", &hosts));
+        assert!(!is_allowed_host("example.com", &hosts));
+        assert!(!is_allowed_host("maps.example.com.evil.org", &hosts));
+        assert!(is_allowed_host("anything.org", &[]));
+    }
+}
@@ -0,0 +1,246 @@
+//! Talks to the external console (econ) of a running game server, so new
+//! votes are loaded right after they are written instead of on the next map
+//! change. The econ is a line based TCP protocol: the server asks for the
+//! password, confirms the authentication and then executes every line it
+//! receives, answering with its console output.
+
+use rocket::serde::Deserialize;
+use rocket::tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    runtime::Handle,
+    time::timeout,
+};
+use std::time::Duration;
+
+/// How long connecting and every answer of the server may take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long we keep logging the output of the command after its last line.
+const OUTPUT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many lines the server may send before asking for the password.
+const MAX_GREETING_LINES: usize = 16;
+/// How many bytes a line of the server may have, including the line ending.
+const MAX_LINE_LEN: u64 = 8192;
+
+fn default_command() -> String {
+    "exec votes.cfg".to_owned()
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct EconConfig {
+    /// The `ec_bindaddr` and `ec_port` of the server.
+    pub address: String,
+    /// The `ec_password` of the server.
+    pub password: String,
+    /// The command which loads the new votes.
+    #[serde(default = "default_command")]
+    pub command: String,
+}
+
+#[derive(Debug, derive_more::Display)]
+pub enum EconError {
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "the server did not answer in time")]
+    Timeout,
+    #[display(fmt = "the server closed the connection")]
+    Closed,
+    #[display(fmt = "the server did not ask for the password")]
+    NoPasswordPrompt,
+    #[display(
+        fmt = "the server sent a line longer than {} bytes",
+        MAX_LINE_LEN
+    )]
+    LineTooLong,
+    #[display(fmt = "authentication failed: {}", _0)]
+    Authentication(String),
+}
+
+impl std::error::Error for EconError {}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    /// Reads the next line, without the line ending and the padding some
+    /// server versions send.
+    async fn read_line(&mut self, wait: Duration) -> Result<String, EconError> {
+        let mut line = Vec::new();
+        let mut limited = (&mut self.reader).take(MAX_LINE_LEN);
+        let read = timeout(wait, limited.read_until(b'\n', &mut line))
+            .await
+            .map_err(|_| EconError::Timeout)?
+            .map_err(EconError::Io)?;
+        if read == 0 {
+            return Err(EconError::Closed);
+        }
+        if read as u64 == MAX_LINE_LEN && !line.ends_with(b"\n") {
+            return Err(EconError::LineTooLong);
+        }
+        Ok(String::from_utf8_lossy(&line)
+            .trim_end_matches(['\r', '\n', '\0'])
+            .to_owned())
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<(), EconError> {
+        let stream = self.reader.get_mut();
+        stream
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .map_err(EconError::Io)
+    }
+}
+
+/// Connects to the econ, authenticates and sends the command. Returns the
+/// console output the command produced.
+pub async fn send(config: &EconConfig) -> Result<Vec<String>, EconError> {
+    let stream = timeout(TIMEOUT, TcpStream::connect(&config.address))
+        .await
+        .map_err(|_| EconError::Timeout)?
+        .map_err(EconError::Io)?;
+    let mut connection = Connection {
+        reader: BufReader::new(stream),
+    };
+
+    // the server greets with "Enter password:"
+    let mut prompted = false;
+    for _ in 0..MAX_GREETING_LINES {
+        let line = connection.read_line(TIMEOUT).await?;
+        if line.to_lowercase().contains("password") {
+            prompted = true;
+            break;
+        }
+    }
+    if !prompted {
+        return Err(EconError::NoPasswordPrompt);
+    }
+    connection.write_line(&config.password).await?;
+    let answer = connection.read_line(TIMEOUT).await?;
+    if !answer.starts_with("Authentication successful") {
+        return Err(EconError::Authentication(answer));
+    }
+
+    connection.write_line(&config.command).await?;
+    let mut output = Vec::new();
+    loop {
+        match connection.read_line(OUTPUT_TIMEOUT).await {
+            Ok(line) => output.push(line),
+            Err(EconError::Timeout) | Err(EconError::Closed) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(output)
+}
+
+/// Sends the command of the target in the background and logs the result.
+/// Does nothing outside of the async runtime.
+pub fn push(target: &str, config: &EconConfig) {
+    let handle = match Handle::try_current() {
+        Ok(handle) => handle,
+        Err(_) => return,
+    };
+    let (target, config) = (target.to_owned(), config.clone());
+    handle.spawn(async move {
+        match send(&config).await {
+            Ok(output) => {
+                println!(
+                    "Sent \"{}\" to target \"{}\" ({})",
+                    config.command, target, config.address
+                );
+                for line in output {
+                    println!("  {}", line);
+                }
+            }
+            Err(e) => eprintln!(
+                "Could not send \"{}\" to target \"{}\" ({}): {}",
+                config.command, target, config.address, e
+            ),
+        }
+    });
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../examples/fake_econ.rs"]
+mod fake_econ;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    /// Serves every connection on a free port with `handle`.
+    fn serve<F>(handle: F) -> SocketAddr
+    where
+        F: Fn(TcpStream) + Send + Copy + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || handle(stream));
+            }
+        });
+        address
+    }
+
+    fn config(address: SocketAddr, password: &str) -> EconConfig {
+        EconConfig {
+            address: address.to_string(),
+            password: password.to_owned(),
+            command: default_command(),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn sends_the_command() {
+        let address = serve(|stream| {
+            let _ = fake_econ::handle(stream, "secret");
+        });
+        let output = send(&config(address, "secret")).await.unwrap();
+        assert_eq!(output, vec!["[Console]: executed 'exec votes.cfg'"]);
+    }
+
+    #[rocket::async_test]
+    async fn rejects_a_wrong_password() {
+        let address = serve(|stream| {
+            let _ = fake_econ::handle(stream, "secret");
+        });
+        match send(&config(address, "wrong")).await {
+            Err(EconError::Authentication(answer)) => {
+                assert_eq!(answer, "Wrong password 1/3.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn stops_waiting_for_the_prompt() {
+        let address = serve(|mut stream| {
+            for _ in 0..MAX_GREETING_LINES * 2 {
+                let _ = stream.write_all(b"Welcome!\r\n");
+            }
+        });
+        assert!(matches!(
+            send(&config(address, "secret")).await,
+            Err(EconError::NoPasswordPrompt)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn rejects_endless_lines() {
+        let address = serve(|mut stream| {
+            let line = vec![b'a'; MAX_LINE_LEN as usize * 2];
+            let _ = stream.write_all(&line);
+        });
+        assert!(matches!(
+            send(&config(address, "secret")).await,
+            Err(EconError::LineTooLong)
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! All changes to the map folders go through here.
+//!
+//! Files are written to a temporary file next to their target and renamed
+//! into place, so a game server never sees a half written map. Changes which
+//! belong to a database update are journaled as a [`PendingFileOp`] in the
+//! same transaction and applied by [`complete`] after the commit. Operations
+//! interrupted by a crash are replayed by [`replay`] on startup, so the map
+//! folders always converge to what the database says.
+
+use crate::get_current_time;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use structsy::{Ref, Structsy, StructsyTx};
+use structsy_derive::{Persistent, PersistentEmbedded};
+
+#[derive(PersistentEmbedded, Debug, Clone, Copy, PartialEq)]
+pub enum FileAction {
+    /// Atomically replaces the target with a copy of the source.
+    Copy,
+    /// Moves the source to the target.
+    Rename,
+    /// Removes the target, if it exists.
+    Remove,
+}
+
+#[derive(Persistent, Debug)]
+pub struct PendingFileOp {
+    pub action: FileAction,
+    pub source: Option<String>,
+    pub target: String,
+    pub created_at: u64,
+}
+
+impl PendingFileOp {
+    fn new(action: FileAction, source: Option<&Path>, target: &Path) -> Self {
+        PendingFileOp {
+            action,
+            source: source.map(|p| p.to_string_lossy().into_owned()),
+            target: target.to_string_lossy().into_owned(),
+            // only used to order the replay, so a broken clock is no reason
+            // to fail
+            created_at: get_current_time().unwrap_or_default(),
+        }
+    }
+
+    pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(source: P, target: Q) -> Self {
+        Self::new(FileAction::Copy, Some(source.as_ref()), target.as_ref())
+    }
+
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        source: P,
+        target: Q,
+    ) -> Self {
+        Self::new(FileAction::Rename, Some(source.as_ref()), target.as_ref())
+    }
+
+    pub fn remove<P: AsRef<Path>>(target: P) -> Self {
+        Self::new(FileAction::Remove, None, target.as_ref())
+    }
+
+    /// Applies the operation. Running it again after it succeeded, or after
+    /// it was interrupted, is harmless.
+    fn run(&self) -> io::Result<()> {
+        let target = Path::new(&self.target);
+        let source = || {
+            self.source.as_ref().map(Path::new).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "missing source")
+            })
+        };
+        match self.action {
+            FileAction::Copy => write_atomic(target, &fs::read(source()?)?),
+            FileAction::Rename => {
+                let source = source()?;
+                // the rename already happened before we were interrupted
+                if !source.exists() && target.exists() {
+                    return Ok(());
+                }
+                rename_file(source, target)
+            }
+            FileAction::Remove => match fs::remove_file(target) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+/// Counts the temporary files, so two writers of the same file never share
+/// one.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// Writes the file to a temporary file in the same folder and renames it into
+/// place once it is on disk.
+pub fn write_atomic<P: AsRef<Path>>(path: P, bytes: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = temp_path(path);
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Moves the file with a rename, falling back to an atomic copy if both
+/// paths are on different file systems.
+pub fn rename_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_err() {
+        write_atomic(to, &fs::read(from)?)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Applies a journaled operation after its transaction was committed and
+/// removes it from the journal. If it fails, it stays journaled and is
+/// retried on the next startup.
+pub fn complete(
+    db: &Structsy,
+    id: Ref<PendingFileOp>,
+    op: &PendingFileOp,
+) -> Result<(), Box<dyn Error>> {
+    op.run()?;
+    let mut tx = db.begin()?;
+    tx.delete(&id)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Applies all operations which were journaled but never completed and
+/// returns how many were applied. Operations which fail again stay
+/// journaled.
+pub fn replay(db: &Structsy) -> usize {
+    let mut pending =
+        db.query::<PendingFileOp>().into_iter().collect::<Vec<_>>();
+    pending.sort_by_key(|(_id, op)| op.created_at);
+
+    let mut count = 0;
+    for (id, op) in pending {
+        println!("Replaying {:?} of \"{}\"", op.action, op.target);
+        match complete(db, id, &op) {
+            Ok(()) => count += 1,
+            Err(e) => eprintln!(
+                "Could not replay {:?} of \"{}\": {}",
+                op.action, op.target, e
+            ),
+        }
+    }
+    count
+}
+
+/// The names and paths of all map files directly inside the folder, skipping
+/// our temporary files. A missing folder has no maps.
+pub fn map_files<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(String, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut maps = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) if !name.starts_with('.') => name.to_owned(),
+            _ => continue,
+        };
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "map") {
+            maps.push((name, path));
+        }
+    }
+    maps.sort();
+    Ok(maps)
+}
@@ -0,0 +1,26 @@
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// The checksums used by the clients to identify a map: teeworlds uses the
+/// CRC32 of the map file, ddnet additionally its SHA-256.
+pub struct MapHashes {
+    pub sha256: String,
+    pub crc32: u32,
+}
+
+impl MapHashes {
+    pub fn of(bytes: &[u8]) -> MapHashes {
+        let sha256 = Sha256::digest(bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        MapHashes {
+            sha256,
+            crc32: crc32fast::hash(bytes),
+        }
+    }
+
+    pub fn of_file<P: AsRef<Path>>(path: P) -> std::io::Result<MapHashes> {
+        Ok(MapHashes::of(&std::fs::read(path)?))
+    }
+}
@@ -0,0 +1,168 @@
+//! Imports map folders which were managed by hand before mapmaster, laid out
+//! like ours: published maps in a folder per difficulty and the maps being
+//! tested in a folder of their own. Run as `mapmaster import`.
+
+use crate::audit::{AuditAction, AuditEvent};
+use crate::datafile::Datafile;
+use crate::files::{self, PendingFileOp};
+use crate::hashes::MapHashes;
+use crate::revisions::{self, Revision};
+use crate::{
+    find_map, get_current_time, map_name, store_preview, update_votes,
+    Difficulty, Map, MapState, CONFIG,
+};
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use structsy::{Structsy, StructsyTx};
+
+/// The identity recorded as uploader of imported maps.
+const UPLOADER: &str = "import";
+
+/// When the file was last modified, as the best guess when the map was
+/// added.
+fn modified_at(path: &Path) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|time| time.as_secs())
+        .or_else(|| get_current_time().ok())
+        .unwrap_or_default()
+}
+
+fn import_map(
+    db: &Structsy,
+    name: String,
+    path: &Path,
+    difficulty: Difficulty,
+    state: MapState,
+) -> Result<(), Box<dyn Error>> {
+    let file = std::fs::read(path)?;
+    let datafile = Datafile::parse(&file)?;
+    let info = datafile.map_info();
+    let created_at = modified_at(path);
+
+    let hashes = MapHashes::of(&file);
+    revisions::store_file(&file, &hashes)?;
+    let revision = Revision {
+        map: name.clone(),
+        sha256: hashes.sha256,
+        crc32: hashes.crc32,
+        uploader: UPLOADER.to_owned(),
+        created_at,
+        changelog: Some(format!("Imported from \"{}\"", path.display())),
+    };
+    let map = Map {
+        name,
+        difficulty,
+        state,
+        created_at,
+        last_changed: created_at,
+        author: info.author,
+        version: info.version,
+        credits: info.credits,
+        license: info.license,
+        sha256: Some(revision.sha256.clone()),
+        crc32: Some(revision.crc32),
+        tags: Vec::new(),
+        candidate: None,
+    };
+
+    if let Err(e) = store_preview(&map.name, &datafile) {
+        eprintln!("Could not render a preview of \"{}\": {}", map.name, e);
+    }
+
+    let op = PendingFileOp::copy(revision.path(), map.path());
+    let event = AuditEvent::new(UPLOADER, AuditAction::Import)
+        .after(&map)
+        .details(format!("revision {}", revision.sha256));
+    let mut tx = db.begin()?;
+    tx.insert(&map)?;
+    tx.insert(&event)?;
+    tx.insert(&revision)?;
+    let op_id = tx.insert(&op)?;
+    tx.commit()?;
+    files::complete(db, op_id, &op)
+}
+
+/// Imports all maps below `published` and in `test`, which default to our
+/// own map folders. Maps which already exist, files with the same name in
+/// several folders and files which would overwrite another one are reported
+/// and skipped.
+pub fn run(
+    db: &Structsy,
+    published: Option<PathBuf>,
+    test: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let published =
+        published.unwrap_or_else(|| CONFIG.public_map_folder.clone());
+    let test = test.unwrap_or_else(|| CONFIG.test_map_folder.clone());
+
+    // published maps come first, so they win over a copy in the test folder
+    let mut found = Vec::new();
+    for difficulty in Difficulty::ALL {
+        for (name, path) in files::map_files(published.join(difficulty))? {
+            found.push((name, path, difficulty, MapState::Published));
+        }
+    }
+    for (name, path) in files::map_files(&test)? {
+        // the test folder doesn't tell the difficulty, it has to be changed
+        // afterwards
+        found.push((name, path, Difficulty::Easy, MapState::New));
+    }
+
+    let mut seen = HashSet::new();
+    let (mut imported, mut skipped) = (0, 0);
+    for (name, path, difficulty, state) in found {
+        let name = match map_name(&name) {
+            Ok(name) => name,
+            Err((_status, error)) => {
+                println!("Skipping \"{}\": {}", path.display(), error.msg);
+                skipped += 1;
+                continue;
+            }
+        };
+        let conflict = if !seen.insert(name.clone()) {
+            Some(format!("another file named \"{}\" was found first", name))
+        } else if find_map(db, &name).is_some() {
+            Some(format!("the map \"{}\" already exists", name))
+        } else {
+            None
+        };
+        let conflict = conflict.or_else(|| {
+            let target = Map::location(&name, difficulty, state);
+            (target.exists() && target != path)
+                .then(|| format!("\"{}\" already exists", target.display()))
+        });
+        if let Some(conflict) = conflict {
+            println!("Skipping \"{}\": {}", path.display(), conflict);
+            skipped += 1;
+            continue;
+        }
+
+        match import_map(db, name, &path, difficulty, state) {
+            Ok(()) => {
+                println!(
+                    "Imported \"{}\" as {:?} {} map",
+                    path.display(),
+                    state,
+                    difficulty
+                );
+                imported += 1;
+            }
+            Err(e) => {
+                println!("Skipping \"{}\": {}", path.display(), e);
+                skipped += 1;
+            }
+        }
+    }
+
+    println!("Imported {} maps, skipped {}", imported, skipped);
+    if imported > 0 {
+        // errors are already logged when they are created
+        let _ = update_votes(db);
+    }
+    Ok(())
+}
@@ -0,0 +1,533 @@
+//! The API keys file. Every line holds a key, its roles and a label:
+//!
+//! ```text
+//! # key                roles          label
+//! 0123456789abcdef     mapper         alice
+//! sha256:9f86d08...    tester,mapper  bob
+//! $argon2id$v=19$...   admin          carol
+//! ```
+//!
+//! Keys are given in plain text, as `sha256:` and the hex digest of the key
+//! or as an argon2 hash, see the `hash-key` command. A key without roles is
+//! an admin key. A key without a label is labeled after its line, e.g.
+//! `key-3`, which changes when lines are added above it. Every
+//! label may be used once, labels starting with `api:` are left to the keys
+//! created through the API. The file is read again on `SIGHUP` and whenever
+//! it changes.
+
+use crate::apikey::Role;
+use crate::hashes::MapHashes;
+use crate::stored_keys::LABEL_PREFIX;
+use argon2::password_hash::{PasswordHash, PasswordVerifier, SaltString};
+use argon2::{Argon2, PasswordHasher};
+use rocket::fairing::AdHoc;
+use rocket::tokio::signal::unix::{signal, SignalKind};
+use rocket::tokio::sync::Semaphore;
+use rocket::tokio::task::spawn_blocking;
+use rocket::tokio::time::interval;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+/// How often the keys file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, derive_more::Display)]
+pub enum KeyFileError {
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "line {}: unknown role \"{}\"", _0, _1)]
+    Role(usize, String),
+    #[display(fmt = "line {}: invalid hash: {}", _0, _1)]
+    Hash(usize, String),
+    #[display(fmt = "the label \"{}\" is used more than once", _0)]
+    DuplicateLabel(String),
+    #[display(fmt = "the label \"{}\" starts with \"{}\"", _0, LABEL_PREFIX)]
+    ReservedLabel(String),
+}
+
+impl std::error::Error for KeyFileError {}
+
+fn sha256(text: &str) -> String {
+    MapHashes::of(text.as_bytes()).sha256
+}
+
+/// Compares without returning early, so the time taken does not tell how
+/// much of a guessed key was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Clone)]
+enum Secret {
+    Plain(String),
+    /// The hex encoded SHA-256 of the key.
+    Sha256(String),
+    /// An argon2 hash in the PHC string format.
+    Argon2(String),
+}
+
+impl Secret {
+    fn parse(secret: &str, line: usize) -> Result<Secret, KeyFileError> {
+        if let Some(digest) = secret.strip_prefix("sha256:") {
+            if digest.len() != 64
+                || !digest.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return Err(KeyFileError::Hash(
+                    line,
+                    "expected 64 hex digits".to_owned(),
+                ));
+            }
+            Ok(Secret::Sha256(digest.to_lowercase()))
+        } else if secret.starts_with("$argon2") {
+            let hash = PasswordHash::new(secret)
+                .map_err(|e| KeyFileError::Hash(line, e.to_string()))?;
+            if hash.hash.is_none() {
+                return Err(KeyFileError::Hash(
+                    line,
+                    "missing hash value".to_owned(),
+                ));
+            }
+            Ok(Secret::Argon2(secret.to_owned()))
+        } else {
+            Ok(Secret::Plain(secret.to_owned()))
+        }
+    }
+
+    fn is_slow(&self) -> bool {
+        matches!(self, Secret::Argon2(_))
+    }
+
+    /// Whether the key matches, `digest` being its SHA-256.
+    fn matches(&self, key: &str, digest: &str) -> bool {
+        match self {
+            Secret::Plain(plain) => {
+                constant_time_eq(plain.as_bytes(), key.as_bytes())
+            }
+            Secret::Sha256(sha256) => {
+                constant_time_eq(sha256.as_bytes(), digest.as_bytes())
+            }
+            Secret::Argon2(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(key.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+/// A key of the keys file.
+pub struct KeyEntry {
+    secret: Secret,
+    pub roles: Vec<Role>,
+    pub label: String,
+}
+
+/// Parses the keys file, skipping empty lines and `#` comments.
+pub fn parse_keys(text: &str) -> Result<Vec<KeyEntry>, KeyFileError> {
+    let mut keys = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let secret = Secret::parse(parts.next().unwrap_or_default(), i + 1)?;
+        let roles = parts
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|role| !role.is_empty())
+            .map(|role| {
+                Role::from_str(role)
+                    .map_err(|_| KeyFileError::Role(i + 1, role.to_owned()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let label = parts.collect::<Vec<_>>().join(" ");
+        keys.push(KeyEntry {
+            // the label is shown in revisions and reviews, so it may not
+            // tell anything about the key
+            label: if label.is_empty() {
+                format!("key-{}", i + 1)
+            } else {
+                label
+            },
+            secret,
+            roles: if roles.is_empty() {
+                vec![Role::Admin]
+            } else {
+                roles
+            },
+        });
+    }
+    Ok(keys)
+}
+
+/// Reads the key to hash from the first line of `input`, to keep it out of
+/// the command line.
+pub fn read_key(mut input: impl BufRead) -> Result<String, String> {
+    let mut line = String::new();
+    input.read_line(&mut line).map_err(|e| e.to_string())?;
+    let key = line.trim_end_matches(['\r', '\n']);
+    if key.is_empty() {
+        return Err("no key given".to_owned());
+    }
+    Ok(key.to_owned())
+}
+
+/// Hashes the key for the keys file, with argon2 unless `sha256` is set.
+pub fn hash_key(key: &str, sha256: bool) -> Result<String, String> {
+    if sha256 {
+        return Ok(format!("sha256:{}", self::sha256(key)));
+    }
+    let salt = SaltString::generate(&mut rand_core::OsRng);
+    Argon2::default()
+        .hash_password(key.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+struct Keys {
+    entries: Vec<KeyEntry>,
+    /// When the file was changed before it was read.
+    modified: Option<SystemTime>,
+    /// The entries of the keys verified so far by their SHA-256, as
+    /// verifying an argon2 hash takes a while.
+    verified: HashMap<String, usize>,
+    /// Counts the reloads.
+    generation: u64,
+}
+
+/// The keys of the keys file, kept up to date while the server runs.
+pub struct KeyStore {
+    path: PathBuf,
+    keys: RwLock<Keys>,
+    /// Bounds the argon2 verifications running at once, as unknown keys are
+    /// verified against every argon2 hash.
+    verifications: Semaphore,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Makes sure every label names a single key, also among the keys created
+/// through the API.
+fn check_labels(entries: &[KeyEntry]) -> Result<(), KeyFileError> {
+    let mut labels = HashSet::new();
+    for entry in entries {
+        if entry.label.starts_with(LABEL_PREFIX) {
+            return Err(KeyFileError::ReservedLabel(entry.label.clone()));
+        }
+        if !labels.insert(&entry.label) {
+            return Err(KeyFileError::DuplicateLabel(entry.label.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the keys file, a missing file has no keys.
+fn read_keys(path: &Path) -> Result<Keys, KeyFileError> {
+    let modified = modified(path);
+    let text = match std::fs::read_to_string(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        text => text.map_err(KeyFileError::Io)?,
+    };
+    let entries = parse_keys(&text)?;
+    check_labels(&entries)?;
+    Ok(Keys {
+        entries,
+        modified,
+        verified: HashMap::new(),
+        generation: 0,
+    })
+}
+
+impl KeyStore {
+    pub fn load(path: PathBuf) -> Result<KeyStore, KeyFileError> {
+        let keys = read_keys(&path)?;
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Ok(KeyStore {
+            path,
+            keys: RwLock::new(keys),
+            verifications: Semaphore::new(cpus),
+        })
+    }
+
+    /// Reads the file again and returns the number of keys. The old keys
+    /// stay in use if it is invalid.
+    pub fn reload(&self) -> Result<usize, KeyFileError> {
+        let result = read_keys(&self.path);
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        match result {
+            Ok(new_keys) => {
+                *keys = Keys {
+                    generation: keys.generation + 1,
+                    ..new_keys
+                };
+                Ok(keys.entries.len())
+            }
+            Err(e) => {
+                // only try again once the file changes again
+                keys.modified = modified(&self.path);
+                Err(e)
+            }
+        }
+    }
+
+    fn changed(&self) -> bool {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        modified(&self.path) != keys.modified
+    }
+
+    /// The labels and the roles of all keys.
+    pub fn entries(&self) -> Vec<(String, Vec<Role>)> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        keys.entries
+            .iter()
+            .map(|entry| (entry.label.clone(), entry.roles.clone()))
+            .collect()
+    }
+
+    /// The label and the roles of the key, if it is in the file.
+    pub async fn find(&self, key: &str) -> Option<(String, Vec<Role>)> {
+        let digest = sha256(key);
+        let found =
+            |entry: &KeyEntry| (entry.label.clone(), entry.roles.clone());
+        // the cheap comparisons first, so only unknown keys and argon2 keys
+        // have to wait for argon2
+        let (slow, generation) = {
+            let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(&i) = keys.verified.get(&digest) {
+                return Some(found(&keys.entries[i]));
+            }
+            if let Some(entry) = keys.entries.iter().find(|entry| {
+                !entry.secret.is_slow() && entry.secret.matches(key, &digest)
+            }) {
+                return Some(found(entry));
+            }
+            let slow = keys
+                .entries
+                .iter()
+                .enumerate()
+                .filter(|(_i, entry)| entry.secret.is_slow())
+                .map(|(i, entry)| (i, entry.secret.clone(), found(entry)))
+                .collect::<Vec<_>>();
+            (slow, keys.generation)
+        };
+        if slow.is_empty() {
+            return None;
+        }
+
+        // argon2 takes a while, so it must neither block the other requests
+        // nor a reload
+        let (key, verify_digest) = (key.to_owned(), digest.clone());
+        let _permit = self.verifications.acquire().await.ok()?;
+        let (i, result) = spawn_blocking(move || {
+            slow.into_iter()
+                .find(|(_i, secret, _found)| {
+                    secret.matches(&key, &verify_digest)
+                })
+                .map(|(i, _secret, found)| (i, found))
+        })
+        .await
+        .ok()??;
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        // the index is only valid for the keys it was found in
+        if keys.generation == generation {
+            keys.verified.insert(digest, i);
+        }
+        Some(result)
+    }
+}
+
+fn reload(store: &KeyStore, reason: &str) {
+    match store.reload() {
+        Ok(count) => println!(
+            "Reloaded {} API keys from \"{}\" ({})",
+            count,
+            store.path.display(),
+            reason
+        ),
+        Err(e) => eprintln!(
+            "Could not reload the API keys from \"{}\", keeping the old \
+            ones: {}",
+            store.path.display(),
+            e
+        ),
+    }
+}
+
+/// Reloads the keys file on `SIGHUP` and whenever it changes.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Reload API keys", |_rocket| {
+        Box::pin(async move {
+            let store = &crate::CONFIG.apikeys;
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(e) => {
+                    eprintln!("Could not listen for SIGHUP: {}", e);
+                    None
+                }
+            };
+            rocket::tokio::spawn(async move {
+                let mut timer = interval(RELOAD_INTERVAL);
+                loop {
+                    let hangup_received = async {
+                        match &mut hangup {
+                            Some(hangup) => hangup.recv().await,
+                            None => std::future::pending().await,
+                        }
+                    };
+                    rocket::tokio::select! {
+                        _ = hangup_received => reload(store, "SIGHUP"),
+                        _ = timer.tick() => {
+                            if store.changed() {
+                                reload(store, "file changed");
+                            }
+                        }
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `secret` hashed with argon2 and SHA-256, see `hash-key`.
+    const ARGON2: &str = "$argon2id$v=19$m=4096,t=3,p=1$Uz9XukD4qjppZibq9UqTWA\
+        $ePESzCDh0emQpYqUlxjBpUqflDkuz5N58ZaqLgA6BVE";
+    const SHA256: &str =
+        "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    #[test]
+    fn parses_all_kinds_of_secrets() {
+        let text = format!(
+            "# key roles label\n\n\
+            plain mapper alice\n\
+            sha256:{} tester,mapper bob the tester\n\
+            {} admin carol\n",
+            SHA256.to_uppercase(),
+            ARGON2
+        );
+        let keys = parse_keys(&text).unwrap();
+        assert_eq!(keys.len(), 3);
+
+        assert_eq!(keys[0].label, "alice");
+        assert_eq!(keys[0].roles, vec![Role::Mapper]);
+        assert!(keys[0].secret.matches("plain", &sha256("plain")));
+        assert!(!keys[0].secret.matches("plan", &sha256("plan")));
+
+        assert_eq!(keys[1].label, "bob the tester");
+        assert_eq!(keys[1].roles, vec![Role::Tester, Role::Mapper]);
+        assert!(keys[1].secret.matches("secret", SHA256));
+        assert!(!keys[1].secret.is_slow());
+
+        assert_eq!(keys[2].label, "carol");
+        assert_eq!(keys[2].roles, vec![Role::Admin]);
+        assert!(keys[2].secret.is_slow());
+        assert!(keys[2].secret.matches("secret", SHA256));
+        assert!(!keys[2].secret.matches("wrong", &sha256("wrong")));
+    }
+
+    #[test]
+    fn keys_without_roles_are_admin_keys() {
+        let keys = parse_keys("plain").unwrap();
+        assert_eq!(keys[0].roles, vec![Role::Admin]);
+    }
+
+    #[test]
+    fn default_labels_name_the_line() {
+        let text = format!("# keys\nsecret\nsha256:{}\n", SHA256);
+        let keys = parse_keys(&text).unwrap();
+        assert_eq!(keys[0].label, "key-2");
+        assert_eq!(keys[1].label, "key-3");
+    }
+
+    #[test]
+    fn reads_the_key_to_hash() {
+        assert_eq!(
+            read_key(&b"secret\r\nmore\n"[..]).ok().as_deref(),
+            Some("secret")
+        );
+        assert!(read_key(&b""[..]).is_err());
+        assert!(read_key(&b"\n"[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_roles() {
+        assert!(matches!(
+            parse_keys("# comment\nplain mapper,wizard"),
+            Err(KeyFileError::Role(2, role)) if role == "wizard"
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_hashes() {
+        assert!(matches!(
+            parse_keys("sha256:1234"),
+            Err(KeyFileError::Hash(1, _))
+        ));
+        assert!(matches!(
+            parse_keys("$argon2id$broken"),
+            Err(KeyFileError::Hash(1, _))
+        ));
+        assert!(matches!(
+            parse_keys("$argon2id$v=19$m=4096,t=3,p=1$Uz9XukD4qjppZibq9UqTWA"),
+            Err(KeyFileError::Hash(1, _))
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn finds_keys_of_all_kinds() {
+        let path = std::env::temp_dir()
+            .join(format!("mapmaster-keys-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!("plain mapper alice\n{} tester carol\n", ARGON2),
+        )
+        .unwrap();
+        let store = KeyStore::load(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let alice = Some(("alice".to_owned(), vec![Role::Mapper]));
+        let carol = Some(("carol".to_owned(), vec![Role::Tester]));
+        assert_eq!(store.find("plain").await, alice);
+        assert_eq!(store.find("secret").await, carol);
+        // verified keys are cached
+        assert_eq!(store.find("secret").await, carol);
+        assert_eq!(store.find("unknown").await, None);
+    }
+
+    #[test]
+    fn labels_name_a_single_key() {
+        let path = std::env::temp_dir()
+            .join(format!("mapmaster-labels-{}", std::process::id()));
+        std::fs::write(&path, "one mapper alice\n").unwrap();
+        let store = KeyStore::load(path.clone()).unwrap();
+
+        std::fs::write(&path, "one mapper alice\ntwo tester alice\n").unwrap();
+        assert!(matches!(
+            store.reload(),
+            Err(KeyFileError::DuplicateLabel(label)) if label == "alice"
+        ));
+        std::fs::write(&path, "one mapper api:alice\n").unwrap();
+        assert!(matches!(
+            store.reload(),
+            Err(KeyFileError::ReservedLabel(label)) if label == "api:alice"
+        ));
+        std::fs::remove_file(&path).unwrap();
+        // the old keys stay in use
+        assert_eq!(
+            store.entries(),
+            vec![("alice".to_owned(), vec![Role::Mapper])]
+        );
+    }
+}
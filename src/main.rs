@@ -3,40 +3,110 @@ extern crate rocket;
 
 use lazy_static::lazy_static;
 use rocket::{
+    data::{ByteUnit, Limits},
+    form::Form,
+    fs::{NamedFile, TempFile},
     http::Status,
     serde::{json::Json, Deserialize, Serialize},
-    State,
+    Build, Rocket, State,
 };
 use rocket_okapi::{
     openapi, openapi_get_routes, rapidoc::*, settings::UrlObject,
 };
 use schemars::JsonSchema;
-use std::{path::Path, str::FromStr, time::SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 use structopt::StructOpt;
 use structsy::{Ref, Structsy, StructsyError, StructsyTx};
 use structsy_derive::{queries, Persistent, PersistentEmbedded};
-use strum::EnumString;
+use strum::{AsRefStr, EnumString};
 
 mod apikey;
+mod approvals;
+mod audit;
 mod common;
 mod config;
+mod datafile;
+mod download;
+mod econ;
+mod files;
+mod hashes;
+mod import;
+mod keys;
+mod migrations;
 mod options;
+mod preview;
+mod purge;
+mod reconcile;
+mod reviews;
+mod revisions;
+mod rotation;
+mod states;
+mod stored_keys;
+mod targets;
+mod votes;
+
+use apikey::{Admin, ApiKey, Mapper, Role, Tester};
+use approvals::{ApprovalVote, Tally, Verdict};
+use audit::{AuditAction, AuditEvent, AuditFilter};
+use common::{MapFile, Paginated};
+use config::{Config, DownloadConfig};
+use datafile::{Datafile, MapInfo};
+use files::PendingFileOp;
+use hashes::MapHashes;
+use keys::KeyStore;
+use options::{Command, Options};
+use reconcile::ReconcileReport;
+use reviews::{Review, ReviewSummary};
+use revisions::Revision;
+use states::{Action, Transition};
+use stored_keys::StoredApiKey;
+
+/// The command line options, the tests have their own.
+#[cfg(not(test))]
+fn options() -> Options {
+    Options::from_args()
+}
 
-use apikey::ApiKey;
-use config::Config;
-use options::Options;
+#[cfg(test)]
+use tests::options;
 
 lazy_static! {
     static ref CONFIG: Config = {
-        let options = Options::from_args();
+        let options = options();
+        let targets = targets::load(&options).unwrap_or_else(|e| {
+            eprintln!("Could not load the targets: {}", e);
+            std::process::exit(1)
+        });
+        let apikeys =
+            KeyStore::load(options.apikeys.clone()).unwrap_or_else(|e| {
+                eprintln!("Could not load the API keys: {}", e);
+                std::process::exit(1)
+            });
         Config {
-            apikeys: std::fs::read_to_string(options.apikeys)
-                .unwrap_or_default()
-                .lines()
-                .map(ToString::to_string)
-                .collect(),
+            apikeys,
             test_map_folder: options.test_maps,
             public_map_folder: options.published_maps,
+            preview_folder: options.previews,
+            revision_folder: options.revisions,
+            declined_retention: Duration::from_secs(
+                options.declined_retention * 60 * 60,
+            ),
+            purge_interval: Duration::from_secs(options.purge_interval * 60),
+            max_upload_size: options.max_upload_size,
+            download: DownloadConfig {
+                timeout: Duration::from_secs(options.download_timeout),
+                max_size: options.max_download_size,
+                retries: options.download_retries,
+                allowed_schemes: options.allowed_schemes,
+                allowed_hosts: options.allowed_hosts,
+                allow_private_hosts: options.allow_private_hosts,
+            },
+            targets,
+            approval_quorum: options.approval_quorum,
             dev: options.dev,
         }
     };
@@ -56,124 +126,56 @@ struct CustomState {
     db: Structsy,
 }
 
-fn map_to_test_vote_string(map: &Map) -> String {
-    let approved = match map.state {
-        MapState::Approved => "☑",
-        MapState::Declined => "☒",
-        _ => "🆕",
-    };
-    let difficulty = format!("[{}]", map.difficulty);
-    let difficulty = match map.difficulty {
-        Difficulty::Insane => format!("{: <8}", difficulty),
-        _ => format!("{: <9}", difficulty),
-    };
-    format!(
-        "add_vote \"{} {} {}\" \"change_map \\\"{}\\\"\"",
-        approved, difficulty, map.name, map.name
-    )
-}
-
-fn map_to_vote_string(map: &Map) -> String {
-    let base = &CONFIG.public_map_folder;
-    let folder = base.join(map.difficulty).join("flexreset.cfg");
-    format!(
-        "add_vote \"{}\" \"sv_reset_file \"{}\"; change_map \\\"{}/{}\\\"\"",
-        map.name,
-        folder.to_string_lossy(),
-        map.difficulty.to_string().to_lowercase(),
-        map.name,
-    )
-}
-
-fn generate_test_votes(maps: &[Map]) -> String {
-    let votes = maps.iter()
-        .map(map_to_test_vote_string)
-        .collect::<Vec<_>>()
-        .join("\n");
-    format!("clear_votes\n{}", votes)
-}
-
-fn generate_published_votes(maps: &[Map]) -> String {
-    let new = maps.iter().take(6);
-    let mut other = maps.iter().skip(6).collect::<Vec<_>>();
-    other.sort_by_key(|m| &m.name);
-    let mut text = vec!["add_vote \"─── NEW MAPS ───\" \"info\"".to_string()];
-    text.extend(new.map(map_to_vote_string));
-    text.push("add_vote \"────────────────\" \"info\"".to_string());
-    text.extend(other.into_iter().map(map_to_vote_string));
-    text.join("\n")
-}
-
 fn update_votes(db: &Structsy) -> Result<(), CustomStatus> {
     let query = db.query::<Map>().fetch();
-    let mut test = Vec::new();
-    let mut easy = Vec::new();
-    let mut main = Vec::new();
-    let mut hard = Vec::new();
-    let mut insane = Vec::new();
-    for map in query.map(|(_id, map)| map) {
-        if [MapState::New, MapState::Approved, MapState::Declined]
-            .contains(&map.state)
-        {
-            test.push(map);
-        } else if map.state == MapState::Published {
-            use Difficulty::*;
-            match map.difficulty {
-                Easy => easy.push(map),
-                Main => main.push(map),
-                Hard => hard.push(map),
-                Insane => insane.push(map),
-            }
+    let mut maps = query.map(|(_id, map)| map).collect::<Vec<_>>();
+    // the candidates of published maps are tested like new maps
+    let candidates = maps
+        .iter()
+        .filter_map(Map::as_candidate)
+        .collect::<Vec<_>>();
+    maps.extend(candidates);
+    maps.sort_by_key(Map::created_at);
+
+    for target in &CONFIG.targets {
+        let matching = maps
+            .iter()
+            .filter(|map| target.matches(map))
+            .collect::<Vec<_>>();
+        files::write_atomic(
+            target.votes_file(),
+            target.template.render(&matching).as_bytes(),
+        )
+        .map_err(|e| {
+            to_internal_server_error(format!(
+                "Could not write the votes of target \"{}\": {}",
+                target.name, e
+            ))
+        })?;
+        if let Some(rotation) = &target.rotation {
+            let entries = rotation.entries(&matching);
+            files::write_atomic(
+                target.rotation_file(),
+                rotation::render_config(&entries).as_bytes(),
+            )
+            .and_then(|_| {
+                files::write_atomic(
+                    target.map_list_file(),
+                    rotation::render_list(&entries).as_bytes(),
+                )
+            })
+            .map_err(|e| {
+                to_internal_server_error(format!(
+                    "Could not write the rotation of target \"{}\": {}",
+                    target.name, e
+                ))
+            })?;
+        }
+        if let Some(econ) = &target.econ {
+            econ::push(&target.name, econ);
         }
     }
 
-    test.sort_by_key(Map::created_at);
-    easy.sort_by_key(Map::created_at);
-    main.sort_by_key(Map::created_at);
-    hard.sort_by_key(Map::created_at);
-    insane.sort_by_key(Map::created_at);
-
-    std::fs::create_dir_all(&CONFIG.test_map_folder)
-        .map_err(to_internal_server_error)?;
-
-    let base = &CONFIG.public_map_folder;
-    let easy_folder = base.join(Difficulty::Easy);
-    let main_folder = base.join(Difficulty::Main);
-    let hard_folder = base.join(Difficulty::Hard);
-    let insane_folder = base.join(Difficulty::Insane);
-
-    std::fs::create_dir_all(&easy_folder).map_err(to_internal_server_error)?;
-    std::fs::create_dir_all(&main_folder).map_err(to_internal_server_error)?;
-    std::fs::create_dir_all(&hard_folder).map_err(to_internal_server_error)?;
-    std::fs::create_dir_all(&insane_folder)
-        .map_err(to_internal_server_error)?;
-
-    std::fs::write(
-        CONFIG.test_map_folder.join("votes.cfg"),
-        generate_test_votes(&test),
-    )
-    .map_err(to_internal_server_error)?;
-    std::fs::write(
-        easy_folder.join("votes.cfg"),
-        generate_published_votes(&easy),
-    )
-    .map_err(to_internal_server_error)?;
-    std::fs::write(
-        main_folder.join("votes.cfg"),
-        generate_published_votes(&main),
-    )
-    .map_err(to_internal_server_error)?;
-    std::fs::write(
-        hard_folder.join("votes.cfg"),
-        generate_published_votes(&hard),
-    )
-    .map_err(to_internal_server_error)?;
-    std::fs::write(
-        insane_folder.join("votes.cfg"),
-        generate_published_votes(&insane),
-    )
-    .map_err(to_internal_server_error)?;
-
     Ok(())
 }
 
@@ -185,6 +187,7 @@ fn update_votes(db: &Structsy) -> Result<(), CustomStatus> {
     PersistentEmbedded,
     Debug,
     EnumString,
+    AsRefStr,
     PartialEq,
     Clone,
     Copy,
@@ -210,6 +213,15 @@ impl std::fmt::Display for Difficulty {
     }
 }
 
+impl Difficulty {
+    const ALL: [Difficulty; 4] = [
+        Difficulty::Easy,
+        Difficulty::Main,
+        Difficulty::Hard,
+        Difficulty::Insane,
+    ];
+}
+
 impl AsRef<Path> for Difficulty {
     fn as_ref(&self) -> &Path {
         use Difficulty::*;
@@ -231,6 +243,7 @@ impl AsRef<Path> for Difficulty {
     PersistentEmbedded,
     Debug,
     EnumString,
+    AsRefStr,
     PartialEq,
     Clone,
     Copy,
@@ -242,24 +255,117 @@ enum MapState {
     Declined,
     Approved,
     Published,
+    /// Declined maps are archived after a while and removed from the test
+    /// servers.
+    Archived,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Persistent, Debug)]
-struct Map {
+impl MapState {
+    const ALL: [MapState; 5] = [
+        MapState::New,
+        MapState::Declined,
+        MapState::Approved,
+        MapState::Published,
+        MapState::Archived,
+    ];
+}
+
+/// A new file of a published map, which is tested while the published file
+/// stays live.
+#[derive(
+    Serialize, Deserialize, JsonSchema, PersistentEmbedded, Debug, Clone,
+)]
+#[serde(crate = "rocket::serde")]
+struct Candidate {
+    sha256: String,
+    crc32: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Persistent, Debug, Clone)]
+#[schemars(rename = "Map")]
+struct MapV5 {
     #[index]
     name: String,
     difficulty: Difficulty,
     state: MapState,
     created_at: u64,
     last_changed: u64,
+    author: Option<String>,
+    version: Option<String>,
+    credits: Option<String>,
+    license: Option<String>,
+    sha256: Option<String>,
+    crc32: Option<u32>,
+    /// Free-form labels, used to pick the maps of a server target.
+    tags: Vec<String>,
+    /// A new upload of a published map, until it is published or dropped.
+    candidate: Option<Candidate>,
 }
 
+/// The current layout of a map, older layouts live in [`migrations`].
+type Map = MapV5;
+
 impl Map {
     fn created_at(&self) -> u64 {
         self.created_at
     }
+
+    /// The location of the map file, which depends on its state.
+    fn path(&self) -> PathBuf {
+        Map::location(&self.name, self.difficulty, self.state)
+    }
+
+    /// The location of the candidate, in the test folder like a new map.
+    fn candidate_path(&self) -> PathBuf {
+        Map::location(&self.name, self.difficulty, MapState::New)
+    }
+
+    /// The file the testers vote on, the candidate if there is one.
+    fn tested_path(&self) -> PathBuf {
+        match self.candidate {
+            Some(_) => self.candidate_path(),
+            None => self.path(),
+        }
+    }
+
+    /// The hash of the file the testers vote on.
+    fn tested_sha256(&self) -> Option<&String> {
+        match &self.candidate {
+            Some(candidate) => Some(&candidate.sha256),
+            None => self.sha256.as_ref(),
+        }
+    }
+
+    /// The candidate as the test servers see it, a new map.
+    fn as_candidate(&self) -> Option<Map> {
+        let candidate = self.candidate.as_ref()?;
+        Some(Map {
+            state: MapState::New,
+            sha256: Some(candidate.sha256.clone()),
+            crc32: Some(candidate.crc32),
+            candidate: None,
+            ..self.clone()
+        })
+    }
+
+    fn location(
+        name: &str,
+        difficulty: Difficulty,
+        state: MapState,
+    ) -> PathBuf {
+        let file_name = format!("{}.map", name);
+        match state {
+            MapState::Published => {
+                CONFIG.public_map_folder.join(difficulty).join(file_name)
+            }
+            _ => CONFIG.test_map_folder.join(file_name),
+        }
+    }
 }
 
+// structsy ignores the embedded enums, so `/list` filters by state and
+// difficulty in `ListFilter::matches`
+#[allow(dead_code)]
 #[queries(Map)]
 trait MapByName {
     fn by_name(self, name: &str) -> Self;
@@ -267,6 +373,7 @@ trait MapByName {
     fn by_difficulty(self, #[allow(unused)] difficulty: &Difficulty) -> Self;
 }
 
+#[allow(dead_code)]
 #[queries(Map)]
 trait MapByState {}
 
@@ -293,6 +400,9 @@ fn add_or_update_map(
     name: String,
     difficulty: Difficulty,
     state: MapState,
+    info: MapInfo,
+    revision: Revision,
+    event: AuditEvent,
 ) -> Result<(), Either<StructsyError, Box<dyn std::error::Error>>> {
     let now = get_current_time()?;
     let my_data = Map {
@@ -301,74 +411,233 @@ fn add_or_update_map(
         state,
         created_at: now,
         last_changed: now,
+        author: info.author,
+        version: info.version,
+        credits: info.credits,
+        license: info.license,
+        sha256: Some(revision.sha256.clone()),
+        crc32: Some(revision.crc32),
+        tags: Vec::new(),
+        candidate: None,
     };
+    let event = event.details(format!("revision {}", revision.sha256));
+    let mut tx = db.begin().map_err(Either::Left)?;
+    let mut ops = Vec::new();
     match find_map(db, &my_data.name) {
         None => {
-            let mut tx = db.begin().map_err(Either::Left)?;
             tx.insert(&my_data).map_err(Either::Left)?;
-            tx.commit().map_err(Either::Left)?;
+            tx.insert(&event.after(&my_data)).map_err(Either::Left)?;
+            ops.push(PendingFileOp::copy(revision.path(), my_data.path()));
         }
         Some((id, map)) => {
-            let mut tx = db.begin().map_err(Either::Left)?;
-            tx.update(
-                &id,
-                &Map {
-                    difficulty,
-                    last_changed: now,
-                    ..map
+            let event = event.before(&map);
+            let published = map.state == MapState::Published;
+            let old_path = map.path();
+            let map = Map {
+                difficulty,
+                last_changed: now,
+                author: my_data.author,
+                version: my_data.version,
+                credits: my_data.credits,
+                license: my_data.license,
+                // uploading an archived map again brings it back, and the
+                // approvals were for the old file
+                state: if [MapState::Archived, MapState::Approved]
+                    .contains(&map.state)
+                {
+                    MapState::New
+                } else {
+                    map.state
                 },
-            )
-            .map_err(Either::Left)?;
-            tx.commit().map_err(Either::Left)?
+                ..map
+            };
+            let map = if published {
+                // the published file stays live while the new one is
+                // tested, uploading the live file again drops the candidate
+                let candidate =
+                    (my_data.sha256 != map.sha256).then(|| Candidate {
+                        sha256: revision.sha256.clone(),
+                        crc32: revision.crc32,
+                    });
+                if map.path() != old_path {
+                    ops.push(PendingFileOp::rename(old_path, map.path()));
+                }
+                ops.push(match candidate {
+                    Some(_) => PendingFileOp::copy(
+                        revision.path(),
+                        map.candidate_path(),
+                    ),
+                    None => PendingFileOp::remove(map.candidate_path()),
+                });
+                Map { candidate, ..map }
+            } else {
+                ops.push(PendingFileOp::copy(revision.path(), map.path()));
+                Map {
+                    sha256: my_data.sha256,
+                    crc32: my_data.crc32,
+                    ..map
+                }
+            };
+            tx.update(&id, &map).map_err(Either::Left)?;
+            tx.insert(&event.after(&map)).map_err(Either::Left)?;
         }
+    };
+    let op_ids = ops
+        .iter()
+        .map(|op| tx.insert(op))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Either::Left)?;
+    tx.insert(&revision).map_err(Either::Left)?;
+    tx.commit().map_err(Either::Left)?;
+    for (op_id, op) in op_ids.into_iter().zip(&ops) {
+        files::complete(db, op_id, op).map_err(Either::Right)?;
     }
-
     Ok(())
 }
 
-fn move_map<P: AsRef<Path>>(from: P, to: P) -> Result<(), std::io::Error> {
-    let p = to.as_ref();
-    if let Some(parent) = p.parent() {
-        std::fs::create_dir_all(parent)?;
+#[derive(FromFormField, JsonSchema, Clone, Copy)]
+#[schemars(rename_all = "snake_case")]
+enum ListSort {
+    Name,
+    #[field(value = "created_at")]
+    CreatedAt,
+    #[field(value = "last_changed")]
+    LastChanged,
+}
+
+#[derive(FromFormField, JsonSchema, Clone, Copy, PartialEq)]
+#[schemars(rename_all = "snake_case")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(FromForm, JsonSchema)]
+struct ListFilter {
+    /// The exact name of the map.
+    name: Option<String>,
+    /// Only maps whose name contains this, ignoring case.
+    search: Option<String>,
+    /// Only maps whose name starts with this, ignoring case.
+    prefix: Option<String>,
+    map_state: Option<MapState>,
+    difficulty: Option<Difficulty>,
+    author: Option<String>,
+    tag: Option<String>,
+    /// Only maps created at or after this unix timestamp.
+    created_after: Option<u64>,
+    /// Only maps created before this unix timestamp.
+    created_before: Option<u64>,
+    /// Only maps changed at or after this unix timestamp.
+    changed_after: Option<u64>,
+    /// Only maps changed before this unix timestamp.
+    changed_before: Option<u64>,
+    /// Sorts by name if not given.
+    sort: Option<ListSort>,
+    order: Option<SortOrder>,
+    /// How many maps to skip.
+    offset: Option<usize>,
+    /// How many maps to return at most, 100 if not given and 1000 at most.
+    limit: Option<usize>,
+}
+
+impl ListFilter {
+    fn matches(&self, map: &Map) -> bool {
+        let contains =
+            |value: &Option<String>, pattern: fn(&str, &str) -> bool| {
+                value.as_ref().is_none_or(|value| {
+                    pattern(&map.name, &value.to_lowercase())
+                })
+            };
+        let in_range = |time: u64, after: Option<u64>, before: Option<u64>| {
+            after.is_none_or(|after| time >= after)
+                && before.is_none_or(|before| time < before)
+        };
+
+        self.map_state.is_none_or(|state| map.state == state)
+            && self.difficulty.is_none_or(|d| map.difficulty == d)
+            && self.author.as_ref().is_none_or(|author| {
+                map.author
+                    .as_ref()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(author))
+            })
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|tag| map.tags.contains(&tag.to_lowercase()))
+            && contains(&self.search, |name, s| name.contains(s))
+            && contains(&self.prefix, |name, p| name.starts_with(p))
+            && in_range(map.created_at, self.created_after, self.created_before)
+            && in_range(
+                map.last_changed,
+                self.changed_after,
+                self.changed_before,
+            )
     }
-    std::fs::copy(&from, to)?;
-    std::fs::remove_file(from)
 }
 
+/// A map together with its reviews and approval votes.
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct MapListing {
+    #[serde(flatten)]
+    map: Map,
+    reviews: ReviewSummary,
+    /// The approval votes for the tested file, the candidate if there is
+    /// one.
+    approvals: Tally,
+}
+
+/// Lists the maps matching the filter. The `X-Total-Count` header holds the
+/// number of all matching maps, regardless of `offset` and `limit`.
 #[openapi]
-#[get("/list?<name>&<map_state>&<difficulty>")]
+#[get("/list?<filter..>")]
 fn list_maps(
     _key: ApiKey,
     state: &State<CustomState>,
-    name: Option<String>,
-    map_state: Option<MapState>,
-    difficulty: Option<Difficulty>,
-) -> Json<Vec<Map>> {
+    filter: ListFilter,
+) -> Paginated<MapListing> {
     let query = state.db.query::<Map>();
 
-    let query = if let Some(name) = name {
-        query.by_name(&name)
+    let query = if let Some(name) = &filter.name {
+        query.by_name(name)
     } else {
         query
     };
 
-    let values = query.into_iter().filter_map(|(_id, map)| {
-        if let Some(map_state) = map_state {
-            if map.state != map_state {
-                return None;
-            }
-        };
-
-        if let Some(difficulty) = difficulty {
-            if map.difficulty != difficulty {
-                return None;
-            }
-        };
+    let mut maps = query
+        .into_iter()
+        .map(|(_id, map)| map)
+        .filter(|map| filter.matches(map))
+        .collect::<Vec<_>>();
 
-        Some(map)
-    });
+    match filter.sort.unwrap_or(ListSort::Name) {
+        ListSort::Name => maps.sort_by(|a, b| a.name.cmp(&b.name)),
+        ListSort::CreatedAt => maps.sort_by_key(|map| map.created_at),
+        ListSort::LastChanged => maps.sort_by_key(|map| map.last_changed),
+    }
+    if filter.order == Some(SortOrder::Desc) {
+        maps.reverse();
+    }
 
-    values.collect::<Vec<_>>().into()
+    let page = Paginated::page(maps, filter.offset, filter.limit);
+    let mut summaries = reviews::summaries(&state.db);
+    let mut tallies = approvals::tallies(&state.db, &page.items);
+    let items = page
+        .items
+        .into_iter()
+        .filter_map(|map| {
+            Some(MapListing {
+                reviews: summaries.remove(&map.name).unwrap_or_default(),
+                approvals: tallies.remove(&map.name)?,
+                map,
+            })
+        })
+        .collect();
+    Paginated {
+        items,
+        total: page.total,
+    }
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -377,6 +646,7 @@ struct CreateMapData<'r> {
     name: &'r str,
     difficulty: &'r str,
     url: &'r str,
+    changelog: Option<&'r str>,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -414,6 +684,17 @@ fn to_custom_bad_request(msg: String) -> CustomStatus {
     )
 }
 
+fn to_forbidden_error(msg: String) -> CustomStatus {
+    eprintln!("{}", msg);
+    (
+        Status::Forbidden,
+        Json(CustomError {
+            msg,
+            code: Status::Forbidden.code,
+        }),
+    )
+}
+
 fn to_internal_server_error<T: ToString>(e: T) -> CustomStatus {
     eprintln!("{}", e.to_string());
     (
@@ -436,37 +717,117 @@ fn to_map_not_found_error<T: ToString>(e: T) -> CustomStatus {
     )
 }
 
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct StateMachine {
+    states: Vec<MapState>,
+    /// Only the transitions available to the map, if one was given.
+    transitions: Vec<&'static Transition>,
+}
+
+/// The states of a map and the actions which move it between them. With
+/// `map`, only the actions valid for that map are listed.
+#[openapi]
+#[get("/states?<map>")]
+fn list_states(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    map: Option<&str>,
+) -> Result<Json<StateMachine>, CustomStatus> {
+    let transitions = match map {
+        Some(name) => {
+            let (_id, map) = find_map(&state.db, name).ok_or_else(|| {
+                to_map_not_found_error(format!("Map \"{}\" not found!", name))
+            })?;
+            states::available(map.state)
+        }
+        None => states::TRANSITIONS.iter().collect(),
+    };
+    Ok(Json(StateMachine {
+        states: MapState::ALL.to_vec(),
+        transitions,
+    }))
+}
+
+/// The state a map ends up in when the testers act on it. The actions on the
+/// candidate of a published map leave the map published.
+fn tested_transition(
+    map: &Map,
+    action: Action,
+) -> Result<MapState, CustomStatus> {
+    match map.candidate {
+        Some(_) => Ok(map.state),
+        None => states::transition(map.state, action)
+            .map_err(|e| to_custom_bad_request(e.to_string())),
+    }
+}
+
 #[openapi]
 #[post("/recall", format = "json", data = "<data>")]
 async fn recall_map(
-    _key: ApiKey,
+    key: Admin,
     state: &State<CustomState>,
     data: Json<JustTheMapName<'_>>,
 ) -> Result<(), CustomStatus> {
     if let Some((id, map)) = find_map(&state.db, data.name) {
-        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-        let map_name = format!("{}.map", map.name);
-        tx.update(
-            &id,
-            &Map {
-                state: MapState::New,
-                last_changed: get_current_time()
-                    .map_err(either_to_custom_status)?,
-                ..map
+        let new_state = states::transition(map.state, Action::Recall)
+            .map_err(|e| to_custom_bad_request(e.to_string()))?;
+        let event = AuditEvent::by(&key, AuditAction::Recall).before(&map);
+        let (source, live) = (map.tested_path(), map.path());
+        let has_candidate = map.candidate.is_some();
+        // the file of an archived map was purged, its last revision comes
+        // back instead
+        let restored = if map.state == MapState::Archived {
+            let revision = revisions::list(&state.db, &map.name)
+                .pop()
+                .ok_or_else(|| {
+                    to_custom_bad_request(format!(
+                        "Map \"{}\" has no revision to restore!",
+                        map.name
+                    ))
+                })?;
+            Some(revision)
+        } else {
+            None
+        };
+        let hashes = match &restored {
+            Some(revision) => MapHashes {
+                sha256: revision.sha256.clone(),
+                crc32: revision.crc32,
             },
-        )
-        .map_err(to_internal_server_error)?;
-
-        if map.state == MapState::Published {
-            let source_dir = CONFIG.public_map_folder.join(map.difficulty);
-            let target_dir = &CONFIG.test_map_folder;
+            None => {
+                MapHashes::of_file(&source).map_err(to_internal_server_error)?
+            }
+        };
+        let map = Map {
+            state: new_state,
+            last_changed: get_current_time()
+                .map_err(either_to_custom_status)?,
+            sha256: Some(hashes.sha256),
+            crc32: Some(hashes.crc32),
+            candidate: None,
+            ..map
+        };
+        let op = match restored {
+            Some(revision) => PendingFileOp::copy(revision.path(), map.path()),
+            // the candidate is already where the recalled map belongs, and
+            // it is newer than the live file
+            None if has_candidate => PendingFileOp::remove(live),
+            None => PendingFileOp::rename(source, map.path()),
+        };
 
-            std::fs::create_dir_all(&target_dir)
-                .map_err(to_internal_server_error)?;
-            move_map(source_dir.join(&map_name), target_dir.join(&map_name))
-                .map_err(to_internal_server_error)?;
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        tx.update(&id, &map).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        // a recalled map has to be approved again
+        for (vote_id, _vote) in approvals::of_map(&state.db, &map.name) {
+            tx.delete(&vote_id).map_err(to_internal_server_error)?;
         }
+        let op_id = tx.insert(&op).map_err(to_internal_server_error)?;
         tx.commit().map_err(to_internal_server_error)?;
+        files::complete(&state.db, op_id, &op)
+            .map_err(to_internal_server_error)?;
         update_votes(&state.db)?;
         Ok(())
     } else {
@@ -480,38 +841,37 @@ async fn recall_map(
 #[openapi]
 #[post("/decline", format = "json", data = "<data>")]
 async fn decline_map(
-    _key: ApiKey,
+    key: Tester,
     state: &State<CustomState>,
     data: Json<JustTheMapName<'_>>,
-) -> Result<(), CustomStatus> {
-    //TODO: Delete Map after 3Days from all Testservers
-    if let Some((id, map)) = find_map(&state.db, &data.name.to_lowercase()) {
-        if [MapState::Approved, MapState::New].contains(&map.state) {
-            let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-            tx.update(
-                &id,
-                &Map {
-                    state: MapState::Declined,
-                    last_changed: get_current_time()
-                        .map_err(either_to_custom_status)?,
-                    ..map
-                },
-            )
+) -> Result<Json<Tally>, CustomStatus> {
+    if let Some((id, map)) = find_map(&state.db, data.name) {
+        let new_state = tested_transition(&map, Action::Decline)?;
+        let now = get_current_time().map_err(either_to_custom_status)?;
+        let vote = ApprovalVote {
+            map: map.name.clone(),
+            reviewer: key.label().to_owned(),
+            verdict: Verdict::Decline,
+            sha256: map.tested_sha256().cloned(),
+            created_at: now,
+        };
+
+        let event = AuditEvent::by(&key, AuditAction::Decline).before(&map);
+        let map = Map {
+            state: new_state,
+            last_changed: now,
+            ..map
+        };
+
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        let tally = approvals::record(&state.db, &mut tx, &map, &vote)
             .map_err(to_internal_server_error)?;
-            tx.commit().map_err(to_internal_server_error)?;
-            update_votes(&state.db)?;
-            Ok(())
-        } else if map.state == MapState::Declined {
-            Err(to_custom_bad_request(
-                "This map is already declined!".to_string(),
-            ))
-        } else {
-            Err(to_custom_bad_request(format!(
-                "Cannot go from state {:?} to {:?}!",
-                map.state,
-                MapState::Declined
-            )))
-        }
+        tx.update(&id, &map).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        update_votes(&state.db)?;
+        Ok(Json(tally))
     } else {
         Err(to_map_not_found_error(format!(
             "Map \"{}\" not found!",
@@ -523,46 +883,46 @@ async fn decline_map(
 #[openapi]
 #[post("/publish", format = "json", data = "<data>")]
 async fn publish_map(
-    _key: ApiKey,
+    key: Admin,
     state: &State<CustomState>,
     data: Json<JustTheMapName<'_>>,
 ) -> Result<(), CustomStatus> {
     if let Some((id, map)) = find_map(&state.db, data.name) {
-        if MapState::Approved == map.state {
-            let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-            let map_name = format!("{}.map", map.name);
-            tx.update(
-                &id,
-                &Map {
-                    state: MapState::Published,
-                    last_changed: get_current_time()
-                        .map_err(either_to_custom_status)?,
-                    ..map
-                },
-            )
-            .map_err(to_internal_server_error)?;
-
-            let source_dir = &CONFIG.test_map_folder;
-            let target_dir = CONFIG.public_map_folder.join(map.difficulty);
-
-            std::fs::create_dir_all(&target_dir)
-                .map_err(to_internal_server_error)?;
-            move_map(source_dir.join(&map_name), target_dir.join(&map_name))
-                .map_err(to_internal_server_error)?;
-            tx.commit().map_err(to_internal_server_error)?;
-            update_votes(&state.db)?;
-            Ok(())
-        } else if MapState::Published == map.state {
-            Err(to_custom_bad_request(
-                "This map is already published!".to_string(),
-            ))
-        } else {
-            Err(to_custom_bad_request(format!(
-                "Cannot go from state {:?} to {:?}!",
-                map.state,
-                MapState::Published
-            )))
+        let new_state = tested_transition(&map, Action::Publish)?;
+        let event = AuditEvent::by(&key, AuditAction::Publish).before(&map);
+        let source = map.tested_path();
+        let hashes =
+            MapHashes::of_file(&source).map_err(to_internal_server_error)?;
+        let map = Map {
+            state: new_state,
+            last_changed: get_current_time()
+                .map_err(either_to_custom_status)?,
+            sha256: Some(hashes.sha256),
+            crc32: Some(hashes.crc32),
+            candidate: None,
+            ..map
+        };
+        // the approvals have to be for the file which goes live
+        let tally = approvals::tally(&state.db, &map);
+        if !tally.reached() {
+            return Err(to_custom_bad_request(format!(
+                "This map file has {} of {} approvals and {} declines!",
+                tally.approvals, tally.required, tally.declines
+            )));
         }
+        // a candidate replaces the live file
+        let op = PendingFileOp::rename(source, map.path());
+
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        tx.update(&id, &map).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        let op_id = tx.insert(&op).map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        files::complete(&state.db, op_id, &op)
+            .map_err(to_internal_server_error)?;
+        update_votes(&state.db)?;
+        Ok(())
     } else {
         Err(to_map_not_found_error(format!(
             "Map \"{}\" not found!",
@@ -574,37 +934,50 @@ async fn publish_map(
 #[openapi]
 #[post("/approve", format = "json", data = "<data>")]
 async fn approve_map(
-    _key: ApiKey,
+    key: Tester,
     state: &State<CustomState>,
     data: Json<JustTheMapName<'_>>,
-) -> Result<(), CustomStatus> {
+) -> Result<Json<Tally>, CustomStatus> {
     if let Some((id, map)) = find_map(&state.db, data.name) {
-        if [MapState::Declined, MapState::New].contains(&map.state) {
-            let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-            tx.update(
-                &id,
-                &Map {
-                    state: MapState::Approved,
-                    last_changed: get_current_time()
-                        .map_err(either_to_custom_status)?,
-                    ..map
-                },
-            )
+        let new_state = tested_transition(&map, Action::Approve)?;
+        let now = get_current_time().map_err(either_to_custom_status)?;
+        let vote = ApprovalVote {
+            map: map.name.clone(),
+            reviewer: key.label().to_owned(),
+            verdict: Verdict::Approve,
+            sha256: map.tested_sha256().cloned(),
+            created_at: now,
+        };
+
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        let tally = approvals::record(&state.db, &mut tx, &map, &vote)
             .map_err(to_internal_server_error)?;
-            tx.commit().map_err(to_internal_server_error)?;
-            update_votes(&state.db)?;
-            Ok(())
-        } else if map.state == MapState::Approved {
-            Err(to_custom_bad_request(
-                "This map is already Approved!".to_string(),
-            ))
+        let event = AuditEvent::by(&key, AuditAction::Approve)
+            .before(&map)
+            .details(format!(
+                "{} of {} approvals, {} declines",
+                tally.approvals, tally.required, tally.declines
+            ));
+        let reached = tally.reached();
+        let map = if reached {
+            Map {
+                state: new_state,
+                last_changed: now,
+                ..map
+            }
         } else {
-            Err(to_custom_bad_request(format!(
-                "Cannot go from state {:?} to {:?}!",
-                map.state,
-                MapState::Approved
-            )))
+            map
+        };
+        if reached {
+            tx.update(&id, &map).map_err(to_internal_server_error)?;
         }
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        if reached {
+            update_votes(&state.db)?;
+        }
+        Ok(Json(tally))
     } else {
         Err(to_map_not_found_error(format!(
             "Map \"{}\" not found!",
@@ -616,7 +989,7 @@ async fn approve_map(
 #[openapi]
 #[post("/change_difficulty", format = "json", data = "<data>")]
 async fn change_map_difficulty(
-    _key: ApiKey,
+    key: Tester,
     state: &State<CustomState>,
     data: Json<ChangeMapDifficultyData<'_>>,
 ) -> Result<(), CustomStatus> {
@@ -624,19 +997,33 @@ async fn change_map_difficulty(
         Difficulty::from_str(data.difficulty).map_err(to_bad_request)?;
 
     if let Some((id, map)) = find_map(&state.db, data.name) {
-        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        let event =
+            AuditEvent::by(&key, AuditAction::ChangeDifficulty).before(&map);
+        let old_path = map.path();
+        let map = Map {
+            difficulty,
+            last_changed: get_current_time()
+                .map_err(either_to_custom_status)?,
+            ..map
+        };
+        // published maps live in the folder of their difficulty
+        let op = (map.state == MapState::Published && map.path() != old_path)
+            .then(|| PendingFileOp::rename(old_path, map.path()));
 
-        tx.update(
-            &id,
-            &Map {
-                difficulty,
-                last_changed: get_current_time()
-                    .map_err(either_to_custom_status)?,
-                ..map
-            },
-        )
-        .map_err(to_internal_server_error)?;
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        tx.update(&id, &map).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        let op_id = op
+            .as_ref()
+            .map(|op| tx.insert(op))
+            .transpose()
+            .map_err(to_internal_server_error)?;
         tx.commit().map_err(to_internal_server_error)?;
+        if let (Some(op_id), Some(op)) = (op_id, &op) {
+            files::complete(&state.db, op_id, op)
+                .map_err(to_internal_server_error)?;
+        }
         update_votes(&state.db)?;
         Ok(())
     } else {
@@ -647,83 +1034,1095 @@ async fn change_map_difficulty(
     }
 }
 
-fn either_to_custom_status(
-    either: Either<StructsyError, Box<dyn std::error::Error>>,
-) -> CustomStatus {
-    use Either::*;
-    match either {
-        Left(l) => to_bad_request(l),
-        Right(r) => to_internal_server_error(r),
-    }
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct ChangeMapTagsData<'r> {
+    name: &'r str,
+    tags: Vec<String>,
 }
 
+/// Replaces the tags of a map.
 #[openapi]
-#[post("/create", format = "json", data = "<data>")]
-async fn create_map(
-    _key: ApiKey,
+#[post("/change_tags", format = "json", data = "<data>")]
+async fn change_map_tags(
+    key: Tester,
     state: &State<CustomState>,
-    data: Json<CreateMapData<'_>>,
+    data: Json<ChangeMapTagsData<'_>>,
 ) -> Result<(), CustomStatus> {
-    let difficulty =
-        Difficulty::from_str(data.difficulty).map_err(to_bad_request)?;
-    let file = reqwest::get(data.url)
-        .await
-        .map_err(to_bad_request)?
-        .bytes()
-        .await
-        .map_err(to_bad_request)?;
-
-    let dir = &CONFIG.test_map_folder;
+    let mut tags = data
+        .tags
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect::<Vec<_>>();
+    tags.sort();
+    tags.dedup();
 
-    std::fs::create_dir_all(&dir).map_err(to_internal_server_error)?;
-
-    let name = data.name.to_lowercase();
+    if let Some((id, map)) = find_map(&state.db, data.name) {
+        let event = AuditEvent::by(&key, AuditAction::ChangeTags)
+            .before(&map)
+            .details(format!("tags: {}", tags.join(", ")));
+        let map = Map {
+            tags,
+            last_changed: get_current_time()
+                .map_err(either_to_custom_status)?,
+            ..map
+        };
 
-    let name = if name.ends_with(".map") {
-        name[0..name.len() - 4].to_string()
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        tx.update(&id, &map).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        update_votes(&state.db)?;
+        Ok(())
     } else {
-        name
-    };
-
-    std::fs::write(dir.join(&format!("{}.map", name)), file)
-        .map_err(to_internal_server_error)?;
-
-    let res = add_or_update_map(&state.db, name, difficulty, MapState::New)
-        .map_err(either_to_custom_status);
-
-    update_votes(&state.db)?;
-
-    res
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            data.name
+        )))
+    }
 }
 
-#[launch]
-fn rocket() -> _ {
-    // this is needed in order to display help texts, because they dont work in lazy_static
-    let _ = Options::from_args();
-
-    let db: Structsy = {
-        let db = Structsy::open("maps.persydb")
-            .expect("could not open database file");
-        db.define::<Map>().unwrap();
-        db
-    };
-
-    println!("Updating maps...");
+/// Removes the map together with its file, its preview, its revisions, its
+/// reviews and its approval votes.
+/// The revision files stay, as other maps may share them.
+#[openapi]
+#[post("/delete", format = "json", data = "<data>")]
+async fn delete_map(
+    key: Admin,
+    state: &State<CustomState>,
+    data: Json<JustTheMapName<'_>>,
+) -> Result<(), CustomStatus> {
+    if let Some((id, map)) = find_map(&state.db, data.name) {
+        let event = AuditEvent::by(&key, AuditAction::Delete).before(&map);
+        let mut ops = vec![
+            PendingFileOp::remove(map.path()),
+            PendingFileOp::remove(
+                CONFIG.preview_folder.join(format!("{}.png", map.name)),
+            ),
+        ];
+        if map.candidate.is_some() {
+            ops.push(PendingFileOp::remove(map.candidate_path()));
+        }
+
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        tx.delete(&id).map_err(to_internal_server_error)?;
+        tx.insert(&event).map_err(to_internal_server_error)?;
+        for (rev_id, _rev) in revisions::of_map(&state.db, &map.name) {
+            tx.delete(&rev_id).map_err(to_internal_server_error)?;
+        }
+        for (review_id, _review) in reviews::of_map(&state.db, &map.name) {
+            tx.delete(&review_id).map_err(to_internal_server_error)?;
+        }
+        for (vote_id, _vote) in approvals::of_map(&state.db, &map.name) {
+            tx.delete(&vote_id).map_err(to_internal_server_error)?;
+        }
+        let op_ids = ops
+            .iter()
+            .map(|op| tx.insert(op))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        for (op_id, op) in op_ids.into_iter().zip(&ops) {
+            files::complete(&state.db, op_id, op)
+                .map_err(to_internal_server_error)?;
+        }
+        update_votes(&state.db)?;
+        Ok(())
+    } else {
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            data.name
+        )))
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct RenameMapData<'r> {
+    name: &'r str,
+    new_name: &'r str,
+}
+
+/// Renames the map, its file, its preview, its revisions, its reviews and its
+/// approval votes in one go.
+#[openapi]
+#[post("/rename", format = "json", data = "<data>")]
+async fn rename_map(
+    key: Admin,
+    state: &State<CustomState>,
+    data: Json<RenameMapData<'_>>,
+) -> Result<(), CustomStatus> {
+    let new_name = map_name(data.new_name)?;
+    if find_map(&state.db, &new_name).is_some() {
+        return Err(to_custom_bad_request(format!(
+            "A map named \"{}\" already exists!",
+            new_name
+        )));
+    }
+
+    if let Some((id, map)) = find_map(&state.db, data.name) {
+        let (old_name, old_path) = (map.name.clone(), map.path());
+        let old_candidate = map
+            .candidate
+            .as_ref()
+            .map(|_candidate| map.candidate_path());
+        let event = AuditEvent::by(&key, AuditAction::Rename)
+            .before(&map)
+            .renamed_from(&old_name);
+        let renamed = Map {
+            name: new_name.clone(),
+            last_changed: get_current_time()
+                .map_err(either_to_custom_status)?,
+            ..map
+        };
+        let preview =
+            |name: &str| CONFIG.preview_folder.join(format!("{}.png", name));
+        let candidate =
+            old_candidate.map(|path| (path, renamed.candidate_path()));
+        let mut ops = Vec::new();
+        for (source, target) in vec![
+            (old_path, renamed.path()),
+            (preview(&old_name), preview(&renamed.name)),
+        ]
+        .into_iter()
+        .chain(candidate)
+        {
+            if target.exists() {
+                return Err(to_custom_bad_request(format!(
+                    "The file \"{}\" already exists!",
+                    target.display()
+                )));
+            }
+            // archived maps have no file and not every map has a preview
+            if source.exists() {
+                ops.push(PendingFileOp::rename(source, target));
+            }
+        }
+
+        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+        tx.update(&id, &renamed).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&renamed))
+            .map_err(to_internal_server_error)?;
+        for (rev_id, rev) in revisions::of_map(&state.db, &old_name) {
+            tx.update(
+                &rev_id,
+                &Revision {
+                    map: new_name.clone(),
+                    ..rev
+                },
+            )
+            .map_err(to_internal_server_error)?;
+        }
+        for (review_id, review) in reviews::of_map(&state.db, &old_name) {
+            tx.update(
+                &review_id,
+                &Review {
+                    map: new_name.clone(),
+                    ..review
+                },
+            )
+            .map_err(to_internal_server_error)?;
+        }
+        for (vote_id, vote) in approvals::of_map(&state.db, &old_name) {
+            tx.update(
+                &vote_id,
+                &ApprovalVote {
+                    map: new_name.clone(),
+                    ..vote
+                },
+            )
+            .map_err(to_internal_server_error)?;
+        }
+        let op_ids = ops
+            .iter()
+            .map(|op| tx.insert(op))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        for (op_id, op) in op_ids.into_iter().zip(&ops) {
+            files::complete(&state.db, op_id, op)
+                .map_err(to_internal_server_error)?;
+        }
+        update_votes(&state.db)?;
+        Ok(())
+    } else {
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            data.name
+        )))
+    }
+}
+
+fn either_to_custom_status(
+    either: Either<StructsyError, Box<dyn std::error::Error>>,
+) -> CustomStatus {
+    use Either::*;
+    match either {
+        Left(l) => to_bad_request(l),
+        Right(r) => to_internal_server_error(r),
+    }
+}
+
+/// The name a map is stored under, lowercase and without the `.map`
+/// extension. Only ASCII letters, digits and `-_.+()[]` are accepted, so names
+/// can neither lead out of the map folders nor break the configs and headers
+/// they end up in.
+fn map_name(name: &str) -> Result<String, CustomStatus> {
+    let lowercase = name.to_lowercase();
+    let stripped = lowercase.strip_suffix(".map").unwrap_or(&lowercase);
+    if stripped.is_empty()
+        || stripped.starts_with('.')
+        || !stripped
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.+()[]".contains(c))
+    {
+        return Err(to_custom_bad_request(format!(
+            "\"{}\" is not a valid map name!",
+            name
+        )));
+    }
+    Ok(stripped.to_owned())
+}
+
+/// Only admins and the mapper who uploaded the first revision of a map may
+/// upload new versions of it.
+fn check_owner(
+    db: &Structsy,
+    key: &ApiKey,
+    name: &str,
+) -> Result<(), CustomStatus> {
+    let name = map_name(name)?;
+    if key.has_role(Role::Admin) || find_map(db, &name).is_none() {
+        return Ok(());
+    }
+    let owner = revisions::list(db, &name)
+        .into_iter()
+        .next()
+        .map(|rev| rev.uploader);
+    if owner.as_deref() == Some(key.label()) {
+        Ok(())
+    } else {
+        Err(to_forbidden_error(format!(
+            "Map \"{}\" belongs to another mapper!",
+            name
+        )))
+    }
+}
+
+/// Validates the map file and stores it as a new revision of the map with
+/// the given name. This is shared by all the ways to upload a map.
+fn store_map(
+    db: &Structsy,
+    event: AuditEvent,
+    name: &str,
+    difficulty: Difficulty,
+    file: &[u8],
+    source: &str,
+    changelog: Option<&str>,
+) -> Result<(), CustomStatus> {
+    let name = map_name(name)?;
+
+    let datafile = Datafile::parse(file).map_err(|e| {
+        to_custom_bad_request(format!(
+            "\"{}\" is not a valid map file: {}",
+            source, e
+        ))
+    })?;
+    println!(
+        "Received map \"{}\" (datafile version {}, {} items, {} data blocks)",
+        name,
+        datafile.version(),
+        datafile.items().len(),
+        datafile.data().len()
+    );
+
+    let hashes = MapHashes::of(file);
+    revisions::store_file(file, &hashes).map_err(to_internal_server_error)?;
+    let revision = Revision {
+        map: name.clone(),
+        sha256: hashes.sha256,
+        crc32: hashes.crc32,
+        uploader: event.actor.clone(),
+        created_at: get_current_time().map_err(either_to_custom_status)?,
+        changelog: changelog.map(ToOwned::to_owned),
+    };
+
+    if let Err(e) = store_preview(&name, &datafile) {
+        eprintln!("Could not render a preview of \"{}\": {}", name, e);
+    }
+
+    let res = add_or_update_map(
+        db,
+        name,
+        difficulty,
+        MapState::New,
+        datafile.map_info(),
+        revision,
+        event,
+    )
+    .map_err(either_to_custom_status);
+
+    update_votes(db)?;
+
+    res
+}
+
+#[openapi]
+#[post("/create", format = "json", data = "<data>")]
+async fn create_map(
+    key: Mapper,
+    state: &State<CustomState>,
+    data: Json<CreateMapData<'_>>,
+) -> Result<(), CustomStatus> {
+    let difficulty =
+        Difficulty::from_str(data.difficulty).map_err(to_bad_request)?;
+    check_owner(&state.db, &key, data.name)?;
+    let file = download::download(data.url).await.map_err(|e| {
+        to_custom_bad_request(format!(
+            "Could not download \"{}\": {}",
+            data.url, e
+        ))
+    })?;
+
+    store_map(
+        &state.db,
+        AuditEvent::by(&key, AuditAction::Upload),
+        data.name,
+        difficulty,
+        &file,
+        data.url,
+        data.changelog,
+    )
+}
+
+#[derive(FromForm, JsonSchema)]
+struct UploadMapData<'r> {
+    #[schemars(with = "Vec<u8>")]
+    file: TempFile<'r>,
+    name: &'r str,
+    difficulty: &'r str,
+    changelog: Option<&'r str>,
+}
+
+/// Same as `/create`, but takes the map file directly as
+/// `multipart/form-data` instead of downloading it.
+#[openapi]
+#[post("/upload", format = "multipart/form-data", data = "<data>")]
+async fn upload_map(
+    key: Mapper,
+    state: &State<CustomState>,
+    data: Form<UploadMapData<'_>>,
+) -> Result<(), CustomStatus> {
+    let difficulty =
+        Difficulty::from_str(data.difficulty).map_err(to_bad_request)?;
+    check_owner(&state.db, &key, data.name)?;
+    let path = data.file.path().ok_or_else(|| {
+        to_custom_bad_request(
+            "The map has to be uploaded as a file!".to_string(),
+        )
+    })?;
+    let file = rocket::tokio::fs::read(path)
+        .await
+        .map_err(to_internal_server_error)?;
+
+    store_map(
+        &state.db,
+        AuditEvent::by(&key, AuditAction::Upload),
+        data.name,
+        difficulty,
+        &file,
+        data.file.name().unwrap_or(data.name),
+        data.changelog,
+    )
+}
+
+fn store_preview(
+    name: &str,
+    datafile: &Datafile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let png = preview::render(datafile)?;
+    files::write_atomic(
+        CONFIG.preview_folder.join(format!("{}.png", name)),
+        &png,
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct MapFileInfo {
+    path: String,
+    size: u64,
+    sha256: String,
+    crc32: u32,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct MapDetails {
+    #[serde(flatten)]
+    map: Map,
+    /// The map file as it is on disk, `None` if there is none.
+    file: Option<MapFileInfo>,
+    /// The approval votes for the tested file, the candidate if there is
+    /// one.
+    approvals: Tally,
+}
+
+/// The record of a single map together with its file on disk.
+#[openapi]
+#[get("/maps/<name>")]
+fn map_details(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> Result<Json<MapDetails>, CustomStatus> {
+    let (_id, map) = find_map(&state.db, name).ok_or_else(|| {
+        to_map_not_found_error(format!("Map \"{}\" not found!", name))
+    })?;
+    let path = map.path();
+    let file = match std::fs::read(&path) {
+        Ok(bytes) => {
+            let hashes = MapHashes::of(&bytes);
+            Some(MapFileInfo {
+                path: path.to_string_lossy().into_owned(),
+                size: bytes.len() as u64,
+                sha256: hashes.sha256,
+                crc32: hashes.crc32,
+            })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(to_internal_server_error(e)),
+    };
+    let approvals = approvals::tally(&state.db, &map);
+    Ok(Json(MapDetails {
+        map,
+        file,
+        approvals,
+    }))
+}
+
+/// Downloads the file of the map from the folder its state places it in.
+#[openapi]
+#[get("/maps/<name>/file")]
+async fn map_file(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> Result<MapFile, CustomStatus> {
+    let (_id, map) = find_map(&state.db, name).ok_or_else(|| {
+        to_map_not_found_error(format!("Map \"{}\" not found!", name))
+    })?;
+    let file = NamedFile::open(map.path())
+        .await
+        .map_err(to_map_not_found_error)?;
+    Ok(MapFile {
+        file,
+        name: map.name,
+    })
+}
+
+/// An overview image of the map with a block of colour per tile. Tiles of
+/// external tilesets, like the standard `grass_main`, are all drawn in the
+/// same colour, as their images are not part of the map.
+#[openapi]
+#[get("/maps/<name>/preview.png")]
+async fn map_preview(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> Result<NamedFile, CustomStatus> {
+    if let Some((_id, map)) = find_map(&state.db, name) {
+        let path = CONFIG.preview_folder.join(format!("{}.png", map.name));
+        NamedFile::open(path).await.map_err(to_map_not_found_error)
+    } else {
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            name
+        )))
+    }
+}
+
+#[openapi]
+#[get("/maps/<name>/revisions")]
+fn list_revisions(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> Result<Json<Vec<Revision>>, CustomStatus> {
+    if let Some((_id, map)) = find_map(&state.db, name) {
+        Ok(revisions::list(&state.db, &map.name).into())
+    } else {
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            name
+        )))
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct ReviewData<'r> {
+    /// From 1 to 5.
+    rating: u8,
+    difficulty: Option<&'r str>,
+    notes: Option<&'r str>,
+}
+
+/// Reviews the map as the holder of the API key, replacing their earlier
+/// review of it.
+#[openapi]
+#[post("/maps/<name>/reviews", format = "json", data = "<data>")]
+fn submit_review(
+    key: Tester,
+    state: &State<CustomState>,
+    name: &str,
+    data: Json<ReviewData<'_>>,
+) -> Result<(), CustomStatus> {
+    if !reviews::RATINGS.contains(&data.rating) {
+        return Err(to_custom_bad_request(format!(
+            "The rating has to be between {} and {}!",
+            reviews::RATINGS.start(),
+            reviews::RATINGS.end()
+        )));
+    }
+    let difficulty = data
+        .difficulty
+        .map(Difficulty::from_str)
+        .transpose()
+        .map_err(to_bad_request)?;
+    let (_id, map) = find_map(&state.db, name).ok_or_else(|| {
+        to_map_not_found_error(format!("Map \"{}\" not found!", name))
+    })?;
+
+    let event = AuditEvent::by(&key, AuditAction::Review)
+        .before(&map)
+        .after(&map)
+        .details(format!("rating {}", data.rating));
+    let review = Review {
+        map: map.name,
+        reviewer: key.label().to_owned(),
+        rating: data.rating,
+        difficulty,
+        notes: data.notes.map(ToOwned::to_owned),
+        created_at: get_current_time().map_err(either_to_custom_status)?,
+    };
+    let earlier = reviews::of_map(&state.db, &review.map)
+        .into_iter()
+        .find(|(_id, earlier)| earlier.reviewer == review.reviewer);
+
+    let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+    match earlier {
+        Some((id, _earlier)) => tx.update(&id, &review),
+        None => tx.insert(&review).map(|_id| ()),
+    }
+    .map_err(to_internal_server_error)?;
+    tx.insert(&event).map_err(to_internal_server_error)?;
+    tx.commit().map_err(to_internal_server_error)?;
+    Ok(())
+}
+
+#[openapi]
+#[get("/maps/<name>/reviews")]
+fn list_reviews(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> Result<Json<Vec<Review>>, CustomStatus> {
+    if let Some((_id, map)) = find_map(&state.db, name) {
+        Ok(reviews::of_map(&state.db, &map.name)
+            .into_iter()
+            .map(|(_id, review)| review)
+            .collect::<Vec<_>>()
+            .into())
+    } else {
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            name
+        )))
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct RollbackData<'r> {
+    sha256: &'r str,
+}
+
+/// Restores a previous revision of the map into the folder of its current
+/// state.
+#[openapi]
+#[post("/maps/<name>/rollback", format = "json", data = "<data>")]
+async fn rollback_map(
+    key: Admin,
+    state: &State<CustomState>,
+    name: &str,
+    data: Json<RollbackData<'_>>,
+) -> Result<(), CustomStatus> {
+    let (id, map) = find_map(&state.db, name).ok_or_else(|| {
+        to_map_not_found_error(format!("Map \"{}\" not found!", name))
+    })?;
+    let revision = revisions::find(&state.db, &map.name, data.sha256)
+        .ok_or_else(|| {
+            to_custom_bad_request(format!(
+                "Map \"{}\" has no revision {}!",
+                map.name, data.sha256
+            ))
+        })?;
+
+    let file =
+        std::fs::read(revision.path()).map_err(to_internal_server_error)?;
+    let datafile = Datafile::parse(&file).map_err(to_internal_server_error)?;
+    let info = datafile.map_info();
+    let now = get_current_time().map_err(either_to_custom_status)?;
+
+    if let Err(e) = store_preview(&map.name, &datafile) {
+        eprintln!("Could not render a preview of \"{}\": {}", map.name, e);
+    }
+
+    let op = PendingFileOp::copy(revision.path(), map.path());
+    let event = AuditEvent::by(&key, AuditAction::Rollback)
+        .before(&map)
+        .details(format!("revision {}", revision.sha256));
+    let map = Map {
+        last_changed: now,
+        author: info.author,
+        version: info.version,
+        credits: info.credits,
+        license: info.license,
+        sha256: Some(revision.sha256.clone()),
+        crc32: Some(revision.crc32),
+        ..map
+    };
+    let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+    tx.update(&id, &map).map_err(to_internal_server_error)?;
+    tx.insert(&event.after(&map))
+        .map_err(to_internal_server_error)?;
+    tx.insert(&Revision {
+        uploader: key.label().to_owned(),
+        created_at: now,
+        changelog: Some(format!("Rollback to revision {}", revision.sha256)),
+        ..revision
+    })
+    .map_err(to_internal_server_error)?;
+    let op_id = tx.insert(&op).map_err(to_internal_server_error)?;
+    tx.commit().map_err(to_internal_server_error)?;
+    files::complete(&state.db, op_id, &op).map_err(to_internal_server_error)?;
+    update_votes(&state.db)?;
+    Ok(())
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde", tag = "problem", rename_all = "snake_case")]
+enum FileProblem {
+    /// The map file does not exist where its state places it.
+    Missing,
+    /// The map was stored before hashes were recorded.
+    NoRecordedHash { sha256: String, crc32: u32 },
+    /// The file on disk is not the one we stored.
+    Mismatch {
+        recorded_sha256: String,
+        recorded_crc32: u32,
+        sha256: String,
+        crc32: u32,
+    },
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct FileCheck {
+    name: String,
+    path: String,
+    #[serde(flatten)]
+    problem: FileProblem,
+}
+
+fn check_map_file(map: &Map) -> Option<FileProblem> {
+    let hashes = match MapHashes::of_file(map.path()) {
+        Ok(hashes) => hashes,
+        Err(_) => return Some(FileProblem::Missing),
+    };
+    match (&map.sha256, map.crc32) {
+        (Some(sha256), Some(crc32))
+            if *sha256 == hashes.sha256 && crc32 == hashes.crc32 =>
+        {
+            None
+        }
+        (Some(sha256), Some(crc32)) => Some(FileProblem::Mismatch {
+            recorded_sha256: sha256.clone(),
+            recorded_crc32: crc32,
+            sha256: hashes.sha256,
+            crc32: hashes.crc32,
+        }),
+        _ => Some(FileProblem::NoRecordedHash {
+            sha256: hashes.sha256,
+            crc32: hashes.crc32,
+        }),
+    }
+}
+
+/// Reports all maps whose file on disk does not match the recorded hashes,
+/// including the candidates of published maps.
+#[openapi]
+#[get("/check")]
+fn check_maps(
+    _key: ApiKey,
+    state: &State<CustomState>,
+) -> Json<Vec<FileCheck>> {
+    let query = state.db.query::<Map>();
+    let maps = query.into_iter().flat_map(|(_id, map)| {
+        let candidate = map.as_candidate();
+        std::iter::once(map).chain(candidate)
+    });
+    let values = maps.filter_map(|map| {
+        if map.state == MapState::Archived {
+            return None;
+        }
+        check_map_file(&map).map(|problem| FileCheck {
+            path: map.path().to_string_lossy().into_owned(),
+            name: map.name,
+            problem,
+        })
+    });
+
+    values.collect::<Vec<_>>().into()
+}
+
+/// Compares the database with the map folders, see [`reconcile`].
+#[openapi]
+#[get("/admin/reconcile")]
+fn reconcile_maps(
+    _key: Admin,
+    state: &State<CustomState>,
+) -> Result<Json<ReconcileReport>, CustomStatus> {
+    reconcile::reconcile(&state.db).map(Json)
+}
+
+/// The log of all changes, the oldest first.
+#[openapi]
+#[get("/audit?<filter..>")]
+fn list_audit_events(
+    _key: Admin,
+    state: &State<CustomState>,
+    filter: AuditFilter,
+) -> Paginated<AuditEvent> {
+    let events = audit::query(&state.db, &filter);
+    Paginated::page(events, filter.offset, filter.limit)
+}
+
+/// Everything that happened to the map, also under earlier names, the
+/// oldest first.
+#[openapi]
+#[get("/maps/<name>/timeline")]
+fn map_timeline(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> Result<Json<Vec<AuditEvent>>, CustomStatus> {
+    if let Some((_id, map)) = find_map(&state.db, name) {
+        Ok(Json(audit::timeline(&state.db, &map.name)))
+    } else {
+        Err(to_map_not_found_error(format!(
+            "Map \"{}\" not found!",
+            name
+        )))
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct CreateKeyData<'r> {
+    /// Identifies the key holder in revisions, reviews and votes, prefixed
+    /// with `api:` to tell it from the labels of the keys file.
+    label: &'r str,
+    roles: Vec<Role>,
+    /// When the key stops working, as a unix timestamp.
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct CreatedKey {
+    /// The label the key acts as.
+    label: String,
+    roles: Vec<Role>,
+    /// The key itself, which is only shown this once.
+    key: String,
+    expires_at: Option<u64>,
+}
+
+/// Creates an API key. Only a hash of it is stored, so the key in the answer
+/// cannot be shown again.
+#[openapi]
+#[post("/admin/keys", format = "json", data = "<data>")]
+fn create_key(
+    key: Admin,
+    state: &State<CustomState>,
+    data: Json<CreateKeyData<'_>>,
+) -> Result<Json<CreatedKey>, CustomStatus> {
+    let label = data.label.trim();
+    let label = label
+        .strip_prefix(stored_keys::LABEL_PREFIX)
+        .unwrap_or(label);
+    if label.is_empty() || data.roles.is_empty() {
+        return Err(to_custom_bad_request(
+            "A key needs a label and at least one role!".to_string(),
+        ));
+    }
+    if stored_keys::find_by_label(&state.db, label).is_some() {
+        return Err(to_custom_bad_request(format!(
+            "A key labeled \"{}\" already exists!",
+            label
+        )));
+    }
+
+    let secret = stored_keys::generate_secret();
+    let stored = StoredApiKey {
+        label: label.to_owned(),
+        roles: data.roles.clone(),
+        sha256: stored_keys::secret_hash(&secret),
+        created_by: key.label().to_owned(),
+        created_at: get_current_time().map_err(either_to_custom_status)?,
+        last_used: None,
+        expires_at: data.expires_at,
+        revoked_at: None,
+    };
+    let event = AuditEvent::by(&key, AuditAction::CreateKey)
+        .details(format!("key \"{}\"", stored.label));
+    let mut tx = state.db.begin().map_err(to_internal_server_error)?;
+    tx.insert(&stored).map_err(to_internal_server_error)?;
+    tx.insert(&event).map_err(to_internal_server_error)?;
+    tx.commit().map_err(to_internal_server_error)?;
+    Ok(Json(CreatedKey {
+        label: stored.actor(),
+        roles: stored.roles,
+        key: secret,
+        expires_at: stored.expires_at,
+    }))
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct KeyListing {
+    /// The label the key acts as, in revisions, reviews and the audit log.
+    label: String,
+    roles: Vec<Role>,
+    /// Keys of the keys file only have a label and roles.
+    from_file: bool,
+    created_by: Option<String>,
+    created_at: Option<u64>,
+    last_used: Option<u64>,
+    expires_at: Option<u64>,
+    revoked_at: Option<u64>,
+}
+
+/// Lists the keys of the keys file and the ones created through the API.
+#[openapi]
+#[get("/admin/keys")]
+fn list_keys(_key: Admin, state: &State<CustomState>) -> Json<Vec<KeyListing>> {
+    let from_file =
+        CONFIG
+            .apikeys
+            .entries()
+            .into_iter()
+            .map(|(label, roles)| KeyListing {
+                label,
+                roles,
+                from_file: true,
+                created_by: None,
+                created_at: None,
+                last_used: None,
+                expires_at: None,
+                revoked_at: None,
+            });
+    let stored =
+        stored_keys::list(&state.db)
+            .into_iter()
+            .map(|stored| KeyListing {
+                label: stored.actor(),
+                roles: stored.roles,
+                from_file: false,
+                created_by: Some(stored.created_by),
+                created_at: Some(stored.created_at),
+                last_used: stored.last_used,
+                expires_at: stored.expires_at,
+                revoked_at: stored.revoked_at,
+            });
+    Json(from_file.chain(stored).collect())
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct ExpireKeyData<'r> {
+    label: &'r str,
+    /// When the key stops working, right away if not set.
+    expires_at: Option<u64>,
+}
+
+/// Changes a key created through the API. Keys of the keys file have to be
+/// removed from the file.
+fn update_stored_key(
+    db: &Structsy,
+    label: &str,
+    event: AuditEvent,
+    update: impl FnOnce(StoredApiKey) -> StoredApiKey,
+) -> Result<(), CustomStatus> {
+    let (id, stored) =
+        stored_keys::find_by_label(db, label).ok_or_else(|| {
+            let in_file = CONFIG
+                .apikeys
+                .entries()
+                .iter()
+                .any(|(l, _roles)| l == label);
+            to_custom_bad_request(if in_file {
+                format!(
+                    "The key \"{}\" is in the keys file and can only be \
+                    removed there!",
+                    label
+                )
+            } else {
+                format!("There is no key labeled \"{}\"!", label)
+            })
+        })?;
+    let mut tx = db.begin().map_err(to_internal_server_error)?;
+    tx.update(&id, &update(stored))
+        .map_err(to_internal_server_error)?;
+    tx.insert(&event.details(format!("key \"{}\"", label)))
+        .map_err(to_internal_server_error)?;
+    tx.commit().map_err(to_internal_server_error)?;
+    Ok(())
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct JustTheKeyLabel<'r> {
+    label: &'r str,
+}
+
+/// Revokes a key for good.
+#[openapi]
+#[post("/admin/keys/revoke", format = "json", data = "<data>")]
+fn revoke_key(
+    key: Admin,
+    state: &State<CustomState>,
+    data: Json<JustTheKeyLabel<'_>>,
+) -> Result<(), CustomStatus> {
+    let now = get_current_time().map_err(either_to_custom_status)?;
+    let event = AuditEvent::by(&key, AuditAction::RevokeKey);
+    update_stored_key(&state.db, data.label, event, |stored| StoredApiKey {
+        revoked_at: stored.revoked_at.or(Some(now)),
+        ..stored
+    })
+}
+
+/// Sets when a key stops working.
+#[openapi]
+#[post("/admin/keys/expire", format = "json", data = "<data>")]
+fn expire_key(
+    key: Admin,
+    state: &State<CustomState>,
+    data: Json<ExpireKeyData<'_>>,
+) -> Result<(), CustomStatus> {
+    let now = get_current_time().map_err(either_to_custom_status)?;
+    let event = AuditEvent::by(&key, AuditAction::ExpireKey);
+    update_stored_key(&state.db, data.label, event, |stored| StoredApiKey {
+        expires_at: Some(data.expires_at.unwrap_or(now)),
+        ..stored
+    })
+}
+
+/// Opens the database and finishes the file operations a crash interrupted.
+fn open_database() -> Structsy {
+    let db: Structsy = migrations::open_database("maps.persydb")
+        .expect("could not open database file");
+    audit::init(&db);
+
+    let replayed = files::replay(&db);
+    if replayed > 0 {
+        println!("Replayed {} pending file operations", replayed);
+    }
+    db
+}
+
+#[rocket::main]
+async fn main() {
+    // this is needed in order to display help texts, because they dont work in lazy_static
+    let options = Options::from_args();
+
+    match options.command {
+        Some(Command::Reconcile { fix }) => {
+            if reconcile::run(&open_database(), fix).is_err() {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::HashKey { sha256 }) => {
+            let hashed = keys::read_key(std::io::stdin().lock())
+                .and_then(|key| keys::hash_key(&key, sha256));
+            match hashed {
+                Ok(hash) => println!("{}", hash),
+                Err(e) => {
+                    eprintln!("Could not hash the key: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::Import { from, test_from }) => {
+            if let Err(e) = import::run(&open_database(), from, test_from) {
+                eprintln!("Import failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        None => {
+            // launch errors are reported when they are dropped
+            let _ = rocket(open_database()).launch().await;
+        }
+    }
+}
+
+fn rocket(db: Structsy) -> Rocket<Build> {
+    println!("Updating maps...");
     let _ = update_votes(&db);
 
     let custom_state = CustomState { db };
 
-    rocket::build()
+    let limits = Limits::default()
+        .limit("file", CONFIG.max_upload_size)
+        .limit("data-form", CONFIG.max_upload_size + ByteUnit::Mebibyte(1));
+    let figment = rocket::Config::figment().merge(("limits", limits));
+
+    rocket::custom(figment)
         .mount(
             "/",
             openapi_get_routes![
                 list_maps,
                 create_map,
+                upload_map,
                 change_map_difficulty,
+                change_map_tags,
+                delete_map,
+                rename_map,
                 approve_map,
                 publish_map,
+                list_states,
                 recall_map,
-                decline_map
+                decline_map,
+                map_details,
+                map_file,
+                map_preview,
+                check_maps,
+                list_revisions,
+                submit_review,
+                list_reviews,
+                map_timeline,
+                rollback_map,
+                reconcile_maps,
+                create_key,
+                list_keys,
+                revoke_key,
+                expire_key,
+                list_audit_events
             ],
         )
         .mount(
@@ -749,5 +2148,201 @@ fn rocket() -> _ {
             }),
         )
         .manage(custom_state)
-        .register("/", catchers![common::bad_request, common::unauthorized])
+        .attach(purge::fairing())
+        .attach(keys::fairing())
+        .attach(audit::fairing())
+        .register(
+            "/",
+            catchers![
+                common::bad_request,
+                common::unauthorized,
+                common::forbidden
+            ],
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::asynchronous::Client;
+
+    /// Holds all files of a test run.
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("mapmaster-test-{}", std::process::id()))
+    }
+
+    pub fn options() -> Options {
+        let dir = test_dir();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        Options::from_iter(vec![
+            "mapmaster".to_owned(),
+            "--dev".to_owned(),
+            "--test-maps".to_owned(),
+            path("maps/test"),
+            "--published-maps".to_owned(),
+            path("maps"),
+            "--previews".to_owned(),
+            path("maps/previews"),
+            "--revisions".to_owned(),
+            path("maps/revisions"),
+            "--apikeys".to_owned(),
+            path("apikeys"),
+        ])
+    }
+
+    fn open_database(name: &str) -> Structsy {
+        std::fs::create_dir_all(test_dir()).unwrap();
+        let path = test_dir().join(format!("{}.persydb", name));
+        migrations::open_database(&path.to_string_lossy()).unwrap()
+    }
+
+    fn map(name: &str, state: MapState, hashes: &MapHashes) -> Map {
+        Map {
+            name: name.to_owned(),
+            difficulty: Difficulty::Easy,
+            state,
+            created_at: 0,
+            last_changed: 0,
+            author: None,
+            version: None,
+            credits: None,
+            license: None,
+            sha256: Some(hashes.sha256.clone()),
+            crc32: Some(hashes.crc32),
+            tags: Vec::new(),
+            candidate: None,
+        }
+    }
+
+    async fn recall(client: &Client, name: &str) -> Status {
+        client
+            .post("/recall")
+            .header(ContentType::JSON)
+            .body(format!("{{\"name\":\"{}\"}}", name))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn moves_published_maps_to_their_new_difficulty() {
+        let db = open_database("difficulty");
+        let file = b"the published map";
+        let hashes = MapHashes::of(file);
+        let published = map("moved", MapState::Published, &hashes);
+        std::fs::create_dir_all(published.path().parent().unwrap()).unwrap();
+        std::fs::write(published.path(), file).unwrap();
+        let mut tx = db.begin().unwrap();
+        tx.insert(&published).unwrap();
+        tx.commit().unwrap();
+
+        let client = Client::tracked(rocket(db.clone())).await.unwrap();
+        let status = client
+            .post("/change_difficulty")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"moved","difficulty":"hard"}"#)
+            .dispatch()
+            .await
+            .status();
+        assert_eq!(status, Status::Ok);
+        let (_id, moved) = find_map(&db, "moved").unwrap();
+        assert_eq!(moved.difficulty, Difficulty::Hard);
+        assert!(!published.path().exists());
+        assert_eq!(std::fs::read(moved.path()).unwrap(), file);
+    }
+
+    #[rocket::async_test]
+    async fn lists_stored_keys_by_the_label_they_act_as() {
+        let db = open_database("keys");
+        let client = Client::tracked(rocket(db)).await.unwrap();
+        let created = client
+            .post("/admin/keys")
+            .header(ContentType::JSON)
+            .body(r#"{"label":"bob","roles":["tester"]}"#)
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(created.contains(r#""label":"api:bob""#), "{}", created);
+
+        let listed = client
+            .get("/admin/keys")
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(listed.contains(r#""label":"api:bob""#), "{}", listed);
+
+        let revoked = client
+            .post("/admin/keys/revoke")
+            .header(ContentType::JSON)
+            .body(r#"{"label":"api:bob"}"#)
+            .dispatch()
+            .await
+            .status();
+        assert_eq!(revoked, Status::Ok);
+    }
+
+    #[test]
+    fn map_names_are_checked() {
+        assert_eq!(
+            map_name("Sunny_Side-2.map").ok().as_deref(),
+            Some("sunny_side-2")
+        );
+        assert_eq!(map_name("run[v1.2]").ok().as_deref(), Some("run[v1.2]"));
+        for name in [
+            "",
+            ".map",
+            ".hidden",
+            "../up",
+            "a\\b",
+            "two words",
+            "quote\"",
+            "semi;colon",
+            "line\nbreak",
+            "bell\u{7}",
+            "ümlaut",
+        ] {
+            assert!(map_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn recalls_archived_maps() {
+        let db = open_database("recall");
+        let file = b"the archived map";
+        let hashes = MapHashes::of(file);
+        revisions::store_file(file, &hashes).unwrap();
+        let mut tx = db.begin().unwrap();
+        tx.insert(&map("archived", MapState::Archived, &hashes))
+            .unwrap();
+        tx.insert(&Revision {
+            map: "archived".to_owned(),
+            sha256: hashes.sha256.clone(),
+            crc32: hashes.crc32,
+            uploader: "mapper".to_owned(),
+            created_at: 0,
+            changelog: None,
+        })
+        .unwrap();
+        tx.insert(&map("lost", MapState::Archived, &hashes))
+            .unwrap();
+        tx.commit().unwrap();
+
+        let client = Client::tracked(rocket(db.clone())).await.unwrap();
+        assert_eq!(recall(&client, "archived").await, Status::Ok);
+        let (_id, recalled) = find_map(&db, "archived").unwrap();
+        assert_eq!(recalled.state, MapState::New);
+        assert_eq!(recalled.sha256, Some(hashes.sha256));
+        assert_eq!(std::fs::read(recalled.path()).unwrap(), file);
+
+        // without a revision there is nothing to bring back
+        assert_eq!(recall(&client, "lost").await, Status::BadRequest);
+        let (_id, lost) = find_map(&db, "lost").unwrap();
+        assert_eq!(lost.state, MapState::Archived);
+    }
 }
@@ -0,0 +1,246 @@
+//! Old layouts of our persistent types.
+//!
+//! Structsy identifies a persistent struct by its name and field layout, so
+//! every change to [`crate::Map`] needs the previous layout kept here, together
+//! with a `From` implementation that converts it into the next version. Each
+//! version is migrated in order by [`open_database`].
+
+use structsy::{SRes, Structsy};
+use structsy_derive::PersistentEmbedded;
+
+/// The map states before maps could be archived.
+#[derive(PersistentEmbedded)]
+pub enum MapState {
+    New,
+    Declined,
+    Approved,
+    Published,
+}
+
+impl From<MapState> for crate::MapState {
+    fn from(state: MapState) -> Self {
+        match state {
+            MapState::New => crate::MapState::New,
+            MapState::Declined => crate::MapState::Declined,
+            MapState::Approved => crate::MapState::Approved,
+            MapState::Published => crate::MapState::Published,
+        }
+    }
+}
+
+pub mod v0 {
+    use super::MapState;
+    use crate::Difficulty;
+    use structsy_derive::Persistent;
+
+    /// The original map layout without any metadata.
+    #[derive(Persistent)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+    }
+}
+
+pub mod v1 {
+    use super::MapState;
+    use crate::Difficulty;
+    use structsy_derive::Persistent;
+
+    /// Adds the metadata of the map info item.
+    #[derive(Persistent)]
+    pub struct MapV1 {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub author: Option<String>,
+        pub version: Option<String>,
+        pub credits: Option<String>,
+        pub license: Option<String>,
+    }
+}
+
+impl From<v0::Map> for v1::MapV1 {
+    fn from(map: v0::Map) -> Self {
+        v1::MapV1 {
+            name: map.name,
+            difficulty: map.difficulty,
+            state: map.state,
+            created_at: map.created_at,
+            last_changed: map.last_changed,
+            author: None,
+            version: None,
+            credits: None,
+            license: None,
+        }
+    }
+}
+
+pub mod v2 {
+    use super::MapState;
+    use crate::Difficulty;
+    use structsy_derive::Persistent;
+
+    /// Adds the hashes of the map file.
+    #[derive(Persistent)]
+    pub struct MapV2 {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub author: Option<String>,
+        pub version: Option<String>,
+        pub credits: Option<String>,
+        pub license: Option<String>,
+        pub sha256: Option<String>,
+        pub crc32: Option<u32>,
+    }
+}
+
+impl From<v1::MapV1> for v2::MapV2 {
+    fn from(map: v1::MapV1) -> Self {
+        v2::MapV2 {
+            name: map.name,
+            difficulty: map.difficulty,
+            state: map.state,
+            created_at: map.created_at,
+            last_changed: map.last_changed,
+            author: map.author,
+            version: map.version,
+            credits: map.credits,
+            license: map.license,
+            sha256: None,
+            crc32: None,
+        }
+    }
+}
+
+pub mod v3 {
+    use crate::{Difficulty, MapState};
+    use structsy_derive::Persistent;
+
+    /// Adds archived maps.
+    #[derive(Persistent)]
+    pub struct MapV3 {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub author: Option<String>,
+        pub version: Option<String>,
+        pub credits: Option<String>,
+        pub license: Option<String>,
+        pub sha256: Option<String>,
+        pub crc32: Option<u32>,
+    }
+}
+
+impl From<v2::MapV2> for v3::MapV3 {
+    fn from(map: v2::MapV2) -> Self {
+        v3::MapV3 {
+            name: map.name,
+            difficulty: map.difficulty,
+            state: map.state.into(),
+            created_at: map.created_at,
+            last_changed: map.last_changed,
+            author: map.author,
+            version: map.version,
+            credits: map.credits,
+            license: map.license,
+            sha256: map.sha256,
+            crc32: map.crc32,
+        }
+    }
+}
+
+pub mod v4 {
+    use crate::{Difficulty, MapState};
+    use structsy_derive::Persistent;
+
+    /// Adds tags.
+    #[derive(Persistent)]
+    pub struct MapV4 {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub author: Option<String>,
+        pub version: Option<String>,
+        pub credits: Option<String>,
+        pub license: Option<String>,
+        pub sha256: Option<String>,
+        pub crc32: Option<u32>,
+        pub tags: Vec<String>,
+    }
+}
+
+impl From<v3::MapV3> for v4::MapV4 {
+    fn from(map: v3::MapV3) -> Self {
+        v4::MapV4 {
+            name: map.name,
+            difficulty: map.difficulty,
+            state: map.state,
+            created_at: map.created_at,
+            last_changed: map.last_changed,
+            author: map.author,
+            version: map.version,
+            credits: map.credits,
+            license: map.license,
+            sha256: map.sha256,
+            crc32: map.crc32,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl From<v4::MapV4> for crate::MapV5 {
+    fn from(map: v4::MapV4) -> Self {
+        crate::MapV5 {
+            name: map.name,
+            difficulty: map.difficulty,
+            state: map.state,
+            created_at: map.created_at,
+            last_changed: map.last_changed,
+            author: map.author,
+            version: map.version,
+            credits: map.credits,
+            license: map.license,
+            sha256: map.sha256,
+            crc32: map.crc32,
+            tags: map.tags,
+            candidate: None,
+        }
+    }
+}
+
+/// Opens the database at the given path, migrates all old layouts to the
+/// current ones and defines all persistent types.
+pub fn open_database(path: &str) -> SRes<Structsy> {
+    let prepare = Structsy::prepare_open(path)?;
+    prepare.migrate::<v0::Map, v1::MapV1>()?;
+    prepare.migrate::<v1::MapV1, v2::MapV2>()?;
+    prepare.migrate::<v2::MapV2, v3::MapV3>()?;
+    prepare.migrate::<v3::MapV3, v4::MapV4>()?;
+    prepare.migrate::<v4::MapV4, crate::MapV5>()?;
+    let db = prepare.open()?;
+    db.define::<crate::Map>()?;
+    db.define::<crate::revisions::Revision>()?;
+    db.define::<crate::files::PendingFileOp>()?;
+    db.define::<crate::reviews::Review>()?;
+    db.define::<crate::approvals::ApprovalVote>()?;
+    db.define::<crate::stored_keys::StoredApiKey>()?;
+    db.define::<crate::audit::AuditEvent>()?;
+    Ok(db)
+}
@@ -1,6 +1,17 @@
+use crate::rotation::RotationOrder;
+use rocket::data::ByteUnit;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+/// Parses a count which has to be at least one.
+fn positive(text: &str) -> Result<u64, String> {
+    match text.parse() {
+        Ok(0) => Err("has to be at least 1".to_owned()),
+        Ok(count) => Ok(count),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
 #[derive(StructOpt, Debug)]
 pub struct Options {
     /// The folder to use as a base for all test maps.
@@ -16,7 +27,103 @@ pub struct Options {
     #[structopt(short, long, name = "directory", default_value = "./maps")]
     pub published_maps: PathBuf,
 
-    /// The file which contains the API keys for access.
+    /// The folder in which the rendered map previews are stored.
+    #[structopt(
+        long,
+        name = "preview directory",
+        default_value = "./maps/previews"
+    )]
+    pub previews: PathBuf,
+
+    /// The folder in which every uploaded revision of a map is kept.
+    #[structopt(
+        long,
+        name = "revision directory",
+        default_value = "./maps/revisions"
+    )]
+    pub revisions: PathBuf,
+
+    /// How many hours a declined map stays on the test servers before it is
+    /// archived.
+    #[structopt(long, name = "hours", default_value = "72")]
+    pub declined_retention: u64,
+
+    /// How many minutes to wait between two checks for expired declined maps.
+    #[structopt(
+        long,
+        name = "minutes",
+        default_value = "60",
+        parse(try_from_str = positive)
+    )]
+    pub purge_interval: u64,
+
+    /// The maximum size of a map uploaded through `/upload`, e.g. `16 MiB`.
+    #[structopt(long, name = "size", default_value = "16 MiB")]
+    pub max_upload_size: ByteUnit,
+
+    /// How many seconds a map download for `/create` may take.
+    #[structopt(long, name = "seconds", default_value = "30")]
+    pub download_timeout: u64,
+
+    /// The maximum size of a map downloaded for `/create`, e.g. `16 MiB`.
+    #[structopt(long, name = "download size", default_value = "16 MiB")]
+    pub max_download_size: ByteUnit,
+
+    /// How often a failed map download is retried.
+    #[structopt(long, name = "retries", default_value = "2")]
+    pub download_retries: u32,
+
+    /// The URL schemes maps may be downloaded from.
+    #[structopt(
+        long,
+        name = "schemes",
+        use_delimiter = true,
+        default_value = "https,http"
+    )]
+    pub allowed_schemes: Vec<String>,
+
+    /// The hosts maps may be downloaded from, `*.example.com` allows all
+    /// subdomains. If none are given, all public hosts are allowed.
+    #[structopt(long, name = "hosts", use_delimiter = true)]
+    pub allowed_hosts: Vec<String>,
+
+    /// Allows downloading maps from loopback and private network addresses.
+    #[structopt(long)]
+    pub allow_private_hosts: bool,
+
+    /// The template of the test server's votes, see `templates/` for the
+    /// defaults.
+    #[structopt(long, name = "test template")]
+    pub test_vote_template: Option<PathBuf>,
+
+    /// The template of the published map servers' votes, also used by
+    /// targets without a template of their own.
+    #[structopt(long, name = "published template")]
+    pub published_vote_template: Option<PathBuf>,
+
+    /// A TOML file listing the servers to write vote files for, see
+    /// `templates/targets.toml`. By default, votes are written to the test
+    /// folder and every difficulty folder.
+    #[structopt(long, name = "targets file")]
+    pub targets: Option<PathBuf>,
+
+    /// The order of the map rotation of the default difficulty targets,
+    /// `created_at` or `shuffle`.
+    #[structopt(long, name = "order", default_value = "created_at")]
+    pub rotation_order: RotationOrder,
+
+    /// The seed used to shuffle the map rotation.
+    #[structopt(long, name = "seed", default_value = "0")]
+    pub rotation_seed: u64,
+
+    /// How many API keys have to approve a map before it is approved. A
+    /// single decline blocks the approval.
+    #[structopt(long, name = "approvals", default_value = "1")]
+    pub approval_quorum: usize,
+
+    /// The file which contains the API keys for access, one key per line
+    /// followed by its roles and a label, e.g. `<key> mapper,tester alice`.
+    /// Changes are picked up while running.
     #[structopt(
         short,
         long,
@@ -29,4 +136,56 @@ pub struct Options {
     /// api.
     #[structopt(short, long)]
     pub dev: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Compares the database with the map folders and reports maps without a
+    /// file, files without a map and files in the wrong folder.
+    Reconcile {
+        /// Imports files without a map as new maps and moves misplaced files
+        /// where they belong.
+        #[structopt(long)]
+        fix: bool,
+    },
+    /// Prints the hash of an API key to put into the keys file instead of
+    /// the key itself. The key is read from the first line of stdin, e.g.
+    /// `mapmaster hash-key < keyfile`.
+    HashKey {
+        /// Uses SHA-256 instead of argon2. Only suitable for long random
+        /// keys.
+        #[structopt(long)]
+        sha256: bool,
+    },
+    /// Imports existing map folders into the database. Maps in a difficulty
+    /// folder are imported as published, maps in the test folder as new.
+    Import {
+        /// The folder containing a folder per difficulty, by default the
+        /// published maps folder.
+        #[structopt(long, name = "published directory")]
+        from: Option<PathBuf>,
+        /// The folder containing the test maps, by default the test maps
+        /// folder.
+        #[structopt(long, name = "test directory")]
+        test_from: Option<PathBuf>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purge_interval_is_positive() {
+        let parse = |minutes: &str| {
+            Options::from_iter_safe(["mapmaster", "--purge-interval", minutes])
+                .map(|options| options.purge_interval)
+        };
+        assert_eq!(parse("5").ok(), Some(5));
+        assert!(parse("0").is_err());
+        assert!(parse("soon").is_err());
+    }
 }
@@ -0,0 +1,319 @@
+//! Renders small overview images of maps.
+//!
+//! Every tile is drawn as a flat block of colour: the game layer gets a fixed
+//! colour per tile type and the design layers use the average colour of the
+//! tile in their (embedded) tileset. External tilesets are not shipped with the
+//! map, so their tiles are drawn with a default tileset colour instead.
+
+use crate::datafile::{Datafile, Item};
+use std::convert::{TryFrom, TryInto};
+
+const ITEMTYPE_IMAGE: u16 = 2;
+const ITEMTYPE_GROUP: u16 = 4;
+const ITEMTYPE_LAYER: u16 = 5;
+
+const LAYERTYPE_TILES: i32 = 2;
+const TILESLAYERFLAG_GAME: i32 = 1;
+
+/// The longest side of a rendered preview in pixels.
+const MAX_PREVIEW_SIZE: usize = 1024;
+/// The maximum amount of pixels used for a single tile.
+const MAX_TILE_SIZE: usize = 8;
+
+const BACKGROUND: [u8; 4] = [94, 132, 174, 255];
+const DEFAULT_TILESET_COLOR: [u8; 4] = [122, 104, 84, 255];
+
+#[derive(Debug, derive_more::Display)]
+pub enum PreviewError {
+    #[display(fmt = "the map has no valid game layer")]
+    NoGameLayer,
+    #[display(fmt = "could not encode the preview: {}", _0)]
+    Encoding(png::EncodingError),
+}
+
+impl std::error::Error for PreviewError {}
+
+struct TileLayer<'a> {
+    width: usize,
+    height: usize,
+    flags: i32,
+    color: [u8; 4],
+    image: Option<&'a Item>,
+    tiles: &'a [u8],
+}
+
+fn game_tile_color(index: u8) -> Option<[u8; 4]> {
+    match index {
+        // solid
+        1 => Some([58, 58, 58, 255]),
+        // death
+        2 => Some([196, 40, 40, 255]),
+        // unhookable
+        3 => Some([140, 140, 150, 255]),
+        // freeze and deep freeze
+        9 | 11 => Some([20, 20, 60, 160]),
+        // unfreeze and deep unfreeze
+        12 | 13 => Some([220, 220, 255, 160]),
+        // start and finish
+        33 | 34 => Some([60, 200, 60, 200]),
+        _ => None,
+    }
+}
+
+fn blend(dst: &mut [u8], src: [u8; 4]) {
+    let alpha = u32::from(src[3]);
+    for i in 0..3 {
+        let value =
+            u32::from(src[i]) * alpha + u32::from(dst[i]) * (255 - alpha);
+        dst[i] = (value / 255) as u8;
+    }
+}
+
+fn modulate(a: [u8; 4], b: [u8; 4]) -> [u8; 4] {
+    let mut out = [0; 4];
+    for i in 0..4 {
+        out[i] = ((u32::from(a[i]) * u32::from(b[i])) / 255) as u8;
+    }
+    out
+}
+
+/// Computes the average colour of each of the 256 tiles of an embedded
+/// tileset. Returns `None` for external images.
+fn tileset_colors(datafile: &Datafile, image: &Item) -> Option<Vec<[u8; 4]>> {
+    let width: usize = image.data.get(1)?.to_owned().try_into().ok()?;
+    let height: usize = image.data.get(2)?.to_owned().try_into().ok()?;
+    let external = *image.data.get(3)? != 0;
+    if external || width < 16 || height < 16 {
+        return None;
+    }
+    let index: usize = image.data.get(5)?.to_owned().try_into().ok()?;
+    let pixels = datafile.data().get(index)?;
+    let channels = match pixels.len() / (width * height) {
+        3 => 3,
+        4 => 4,
+        _ => return None,
+    };
+    let (tile_width, tile_height) = (width / 16, height / 16);
+    let colors = (0..256)
+        .map(|tile| {
+            let (tx, ty) = (tile % 16 * tile_width, tile / 16 * tile_height);
+            let mut sum = [0u64; 4];
+            for y in ty..ty + tile_height {
+                for x in tx..tx + tile_width {
+                    let p = &pixels[(y * width + x) * channels..];
+                    let alpha = if channels == 4 { p[3] } else { 255 };
+                    for i in 0..3 {
+                        sum[i] += u64::from(p[i]) * u64::from(alpha);
+                    }
+                    sum[3] += u64::from(alpha);
+                }
+            }
+            if sum[3] == 0 {
+                return [0; 4];
+            }
+            let count = (tile_width * tile_height) as u64;
+            [
+                (sum[0] / sum[3]) as u8,
+                (sum[1] / sum[3]) as u8,
+                (sum[2] / sum[3]) as u8,
+                (sum[3] / count) as u8,
+            ]
+        })
+        .collect();
+    Some(colors)
+}
+
+fn tile_layers(datafile: &Datafile) -> Vec<TileLayer<'_>> {
+    let layers = datafile
+        .items()
+        .iter()
+        .filter(|item| item.type_id == ITEMTYPE_LAYER)
+        .collect::<Vec<_>>();
+    let groups = datafile
+        .items()
+        .iter()
+        .filter(|item| item.type_id == ITEMTYPE_GROUP);
+
+    let mut result = Vec::new();
+    for group in groups {
+        let (start, num) = match (group.data.get(5), group.data.get(6)) {
+            (Some(&start), Some(&num)) => (start, num),
+            _ => continue,
+        };
+        for index in start..start.saturating_add(num) {
+            let layer = match usize::try_from(index)
+                .ok()
+                .and_then(|index| layers.get(index))
+            {
+                Some(layer) => layer,
+                None => continue,
+            };
+            let d = &layer.data;
+            if d.len() < 15 || d[1] != LAYERTYPE_TILES {
+                continue;
+            }
+            let (width, height) = match (d[4].try_into(), d[5].try_into()) {
+                (Ok(width), Ok(height)) => (width, height),
+                _ => continue,
+            };
+            let tiles = match usize::try_from(d[14])
+                .ok()
+                .and_then(|index| datafile.data().get(index))
+            {
+                Some(tiles) if tiles.len() >= width * height * 4 => tiles,
+                _ => continue,
+            };
+            let image = u16::try_from(d[13])
+                .ok()
+                .and_then(|id| datafile.find_item(ITEMTYPE_IMAGE, id));
+            let color =
+                [d[7], d[8], d[9], d[10]].map(|c| c.clamp(0, 255) as u8);
+            result.push(TileLayer {
+                width,
+                height,
+                flags: d[6],
+                color,
+                image,
+                tiles,
+            });
+        }
+    }
+    result
+}
+
+/// Renders an overview of the whole map and returns it encoded as PNG.
+pub fn render(datafile: &Datafile) -> Result<Vec<u8>, PreviewError> {
+    let layers = tile_layers(datafile);
+    let game = layers
+        .iter()
+        .find(|layer| layer.flags & TILESLAYERFLAG_GAME != 0)
+        .filter(|layer| layer.width > 0 && layer.height > 0)
+        .ok_or(PreviewError::NoGameLayer)?;
+
+    let longest = game.width.max(game.height);
+    let (tile_size, step) = if longest * MAX_TILE_SIZE <= MAX_PREVIEW_SIZE {
+        (MAX_TILE_SIZE, 1)
+    } else if longest <= MAX_PREVIEW_SIZE {
+        ((MAX_PREVIEW_SIZE / longest).max(1), 1)
+    } else {
+        (1, longest.div_ceil(MAX_PREVIEW_SIZE))
+    };
+    let columns = game.width.div_ceil(step);
+    let rows = game.height.div_ceil(step);
+    let (width, height) = (columns * tile_size, rows * tile_size);
+
+    let mut pixels = BACKGROUND.repeat(width * height);
+    let mut fill = |column: usize, row: usize, color: [u8; 4]| {
+        for y in row * tile_size..(row + 1) * tile_size {
+            for x in column * tile_size..(column + 1) * tile_size {
+                let offset = (y * width + x) * 4;
+                blend(&mut pixels[offset..offset + 4], color);
+            }
+        }
+    };
+
+    let mut draw =
+        |layer: &TileLayer, color_of: &dyn Fn(u8) -> Option<[u8; 4]>| {
+            for row in 0..rows.min(layer.height.div_ceil(step)) {
+                for column in 0..columns.min(layer.width.div_ceil(step)) {
+                    let offset = (row * step * layer.width + column * step) * 4;
+                    let index = layer.tiles[offset];
+                    if index == 0 {
+                        continue;
+                    }
+                    if let Some(color) = color_of(index) {
+                        fill(column, row, color);
+                    }
+                }
+            }
+        };
+
+    draw(game, &game_tile_color);
+    // the other flags mark the special layers of ddnet (tele, speedup, ...)
+    for layer in layers.iter().filter(|layer| layer.flags == 0) {
+        let colors = layer
+            .image
+            .and_then(|image| tileset_colors(datafile, image));
+        let color_of = |index: u8| {
+            let color = match &colors {
+                Some(colors) => colors[usize::from(index)],
+                None => DEFAULT_TILESET_COLOR,
+            };
+            Some(modulate(color, layer.color))
+        };
+        draw(layer, &color_of);
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(PreviewError::Encoding)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datafile::tests::build;
+
+    /// A white tiles layer item of a single row, `image` -1 for none.
+    fn layer(width: i32, flags: i32, image: i32, data: i32) -> Vec<i32> {
+        let (white, envelope) = ([255; 4], [0, 0]);
+        let header = [0, LAYERTYPE_TILES, 0, 0, width, 1, flags];
+        [&header[..], &white, &envelope, &[image, data]].concat()
+    }
+
+    fn tiles(indices: &[u8]) -> Vec<u8> {
+        indices.iter().flat_map(|&index| [index, 0, 0, 0]).collect()
+    }
+
+    fn pixel(png: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let decoder = png::Decoder::new(png);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        let offset = (y * info.width as usize + x) * 4;
+        pixels[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn renders_game_and_design_tiles() {
+        let group = [0, 0, 0, 0, 0, 0, 2];
+        let game = layer(3, TILESLAYERFLAG_GAME, -1, 0);
+        let design = layer(3, 0, -1, 1);
+        let file = build(
+            &[
+                (ITEMTYPE_GROUP, 0, &group),
+                (ITEMTYPE_LAYER, 0, &game),
+                (ITEMTYPE_LAYER, 1, &design),
+            ],
+            &[tiles(&[1, 0, 0]), tiles(&[0, 5, 0])],
+        );
+        let png = render(&Datafile::parse(&file).unwrap()).unwrap();
+
+        let last = MAX_TILE_SIZE - 1;
+        assert_eq!(pixel(&png, 0, 0), [58, 58, 58, 255]);
+        assert_eq!(pixel(&png, last, last), [58, 58, 58, 255]);
+        // a design layer without an embedded image
+        assert_eq!(pixel(&png, MAX_TILE_SIZE, 0), DEFAULT_TILESET_COLOR);
+        assert_eq!(pixel(&png, 2 * MAX_TILE_SIZE, 0), BACKGROUND);
+    }
+
+    #[test]
+    fn needs_a_game_layer() {
+        let group = [0, 0, 0, 0, 0, 0, 1];
+        let design = layer(1, 0, -1, 0);
+        let file = build(
+            &[(ITEMTYPE_GROUP, 0, &group), (ITEMTYPE_LAYER, 0, &design)],
+            &[tiles(&[1])],
+        );
+        assert!(matches!(
+            render(&Datafile::parse(&file).unwrap()),
+            Err(PreviewError::NoGameLayer)
+        ));
+    }
+}
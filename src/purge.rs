@@ -0,0 +1,121 @@
+//! Declined maps stay on the test servers for a while, so the mapper can still
+//! look at the feedback in-game. Afterwards they are removed from the test
+//! folder and archived by a background task. Declined candidates of published
+//! maps are removed the same way, the published file stays live.
+
+use crate::approvals::{self, Verdict};
+use crate::audit::{AuditAction, AuditEvent};
+use crate::files::{self, PendingFileOp};
+use crate::states::{self, Action};
+use crate::{
+    either_to_custom_status, get_current_time, to_internal_server_error,
+    update_votes, CustomState, CustomStatus, Map, MapState, CONFIG,
+};
+use rocket::fairing::AdHoc;
+use rocket::tokio::time::interval;
+use structsy::{Structsy, StructsyTx};
+
+/// When the testers last declined the candidate of the map, if they
+/// still decline it.
+fn candidate_declined_at(db: &Structsy, map: &Map) -> Option<u64> {
+    map.candidate.as_ref()?;
+    approvals::of_map(db, &map.name)
+        .into_iter()
+        .map(|(_id, vote)| vote)
+        .filter(|vote| {
+            vote.verdict == Verdict::Decline
+                && vote.sha256.as_ref() == map.tested_sha256()
+        })
+        .map(|vote| vote.created_at)
+        .max()
+}
+
+/// Archives all maps which are declined for longer than the retention
+/// period, drops the candidates declined for as long and returns how many
+/// maps were changed.
+pub fn purge_declined_maps(db: &Structsy) -> Result<usize, CustomStatus> {
+    let now = get_current_time().map_err(either_to_custom_status)?;
+    let retention = CONFIG.declined_retention.as_secs();
+    let expired = db
+        .query::<Map>()
+        .into_iter()
+        .filter(|(_id, map)| {
+            let declined_at = match map.state {
+                MapState::Declined => Some(map.last_changed),
+                _ => candidate_declined_at(db, map),
+            };
+            declined_at.is_some_and(|at| at + retention <= now)
+        })
+        .collect::<Vec<_>>();
+    let count = expired.len();
+
+    for (id, map) in expired {
+        let event = AuditEvent::new("purge", AuditAction::Archive).before(&map);
+        // only the tested file goes, a published file stays live
+        let op = PendingFileOp::remove(map.tested_path());
+        let (map, event) = match &map.candidate {
+            Some(candidate) => {
+                println!("Dropping declined candidate of \"{}\"", map.name);
+                let event =
+                    event.details(format!("candidate {}", candidate.sha256));
+                (
+                    Map {
+                        candidate: None,
+                        last_changed: now,
+                        ..map
+                    },
+                    event,
+                )
+            }
+            None => {
+                println!("Archiving declined map \"{}\"", map.name);
+                let new_state = states::transition(map.state, Action::Archive)
+                    .map_err(to_internal_server_error)?;
+                (
+                    Map {
+                        state: new_state,
+                        last_changed: now,
+                        ..map
+                    },
+                    event,
+                )
+            }
+        };
+        let mut tx = db.begin().map_err(to_internal_server_error)?;
+        tx.update(&id, &map).map_err(to_internal_server_error)?;
+        tx.insert(&event.after(&map))
+            .map_err(to_internal_server_error)?;
+        let op_id = tx.insert(&op).map_err(to_internal_server_error)?;
+        tx.commit().map_err(to_internal_server_error)?;
+        files::complete(db, op_id, &op).map_err(to_internal_server_error)?;
+    }
+
+    if count > 0 {
+        update_votes(db)?;
+    }
+    Ok(count)
+}
+
+/// Periodically runs [`purge_declined_maps`] while the server is running.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Purge declined maps", |rocket| {
+        Box::pin(async move {
+            let db = match rocket.state::<CustomState>() {
+                Some(state) => state.db.clone(),
+                None => return,
+            };
+            rocket::tokio::spawn(async move {
+                let mut timer = interval(CONFIG.purge_interval);
+                loop {
+                    timer.tick().await;
+                    let db = db.clone();
+                    // errors are already logged when they are created
+                    let _ = rocket::tokio::task::spawn_blocking(move || {
+                        purge_declined_maps(&db)
+                    })
+                    .await;
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,250 @@
+//! Compares the database with the map folders, since files can be changed
+//! behind our back. Run as `mapmaster reconcile [--fix]` or via
+//! `GET /admin/reconcile`.
+
+use crate::audit::{AuditAction, AuditEvent};
+use crate::files;
+use crate::{
+    find_map, store_map, to_internal_server_error, update_votes, CustomStatus,
+    Difficulty, Map, MapState, CONFIG,
+};
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use structsy::Structsy;
+
+/// The identity recorded as uploader of imported orphans.
+const UPLOADER: &str = "reconcile";
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct MissingFile {
+    pub name: String,
+    pub expected: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct OrphanFile {
+    pub name: String,
+    pub path: String,
+    /// The difficulty of the folder the file was found in, if it is in one.
+    pub difficulty: Option<Difficulty>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct MisplacedFile {
+    pub name: String,
+    pub path: String,
+    /// Where the map belongs, `None` for archived maps, which should have no
+    /// file at all.
+    pub expected: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ReconcileReport {
+    /// Files of maps, or of their candidates, which do not exist.
+    pub missing: Vec<MissingFile>,
+    /// Map files without a map in the database.
+    pub orphans: Vec<OrphanFile>,
+    /// Map files outside of the folder their map's state and difficulty
+    /// place them in.
+    pub misplaced: Vec<MisplacedFile>,
+}
+
+impl ReconcileReport {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty()
+            && self.orphans.is_empty()
+            && self.misplaced.is_empty()
+    }
+}
+
+fn path_string(path: PathBuf) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// All map files in the test folder and the difficulty folders.
+fn scan_map_folders(
+) -> std::io::Result<Vec<(String, PathBuf, Option<Difficulty>)>> {
+    let mut found = files::map_files(&CONFIG.test_map_folder)?
+        .into_iter()
+        .map(|(name, path)| (name, path, None))
+        .collect::<Vec<_>>();
+    for difficulty in Difficulty::ALL {
+        let folder = CONFIG.public_map_folder.join(difficulty);
+        found.extend(
+            files::map_files(folder)?
+                .into_iter()
+                .map(|(name, path)| (name, path, Some(difficulty))),
+        );
+    }
+    Ok(found)
+}
+
+/// Compares the maps in the database with the files in the map folders.
+pub fn reconcile(db: &Structsy) -> Result<ReconcileReport, CustomStatus> {
+    let maps = db
+        .query::<Map>()
+        .into_iter()
+        .map(|(_id, map)| (map.name.clone(), map))
+        .collect::<BTreeMap<_, _>>();
+    let mut report = ReconcileReport::default();
+
+    for (name, path, difficulty) in
+        scan_map_folders().map_err(to_internal_server_error)?
+    {
+        match maps.get(&name.to_lowercase()) {
+            None => report.orphans.push(OrphanFile {
+                name,
+                path: path_string(path),
+                difficulty,
+            }),
+            Some(map) if map.state == MapState::Archived => {
+                report.misplaced.push(MisplacedFile {
+                    name: map.name.clone(),
+                    path: path_string(path),
+                    expected: None,
+                })
+            }
+            // the new upload of a published map, which is tested
+            Some(map)
+                if map.candidate.is_some() && map.candidate_path() == path => {}
+            Some(map) if map.path() != path => {
+                report.misplaced.push(MisplacedFile {
+                    name: map.name.clone(),
+                    path: path_string(path),
+                    expected: Some(path_string(map.path())),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    for map in maps.values() {
+        let misplaced = report.misplaced.iter().any(|f| f.name == map.name);
+        if map.state == MapState::Archived || misplaced {
+            continue;
+        }
+        let candidate = map.candidate.as_ref().map(|_| map.candidate_path());
+        for path in std::iter::once(map.path()).chain(candidate) {
+            if !path.is_file() {
+                report.missing.push(MissingFile {
+                    name: map.name.clone(),
+                    expected: path_string(path),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Moves misplaced files where they belong and imports orphans as new maps.
+/// Missing files can't be fixed here, they have to be uploaded again.
+pub fn fix(
+    db: &Structsy,
+    report: &ReconcileReport,
+) -> Result<(), CustomStatus> {
+    for file in &report.misplaced {
+        match &file.expected {
+            None => {
+                println!("Removing \"{}\" of archived map", file.path);
+                std::fs::remove_file(&file.path)
+                    .map_err(to_internal_server_error)?;
+            }
+            Some(expected) if Path::new(expected).exists() => {
+                println!(
+                    "Keeping \"{}\", because \"{}\" already exists",
+                    file.path, expected
+                );
+            }
+            Some(expected) => {
+                println!("Moving \"{}\" to \"{}\"", file.path, expected);
+                files::rename_file(&file.path, expected)
+                    .map_err(to_internal_server_error)?;
+            }
+        }
+    }
+
+    for orphan in &report.orphans {
+        if find_map(db, &orphan.name).is_some() {
+            println!(
+                "Skipping \"{}\", because \"{}\" was already imported",
+                orphan.path, orphan.name
+            );
+            continue;
+        }
+        println!("Importing \"{}\" as a new map", orphan.path);
+        let file =
+            std::fs::read(&orphan.path).map_err(to_internal_server_error)?;
+        // maps in the test folder don't tell their difficulty, it has to be
+        // changed afterwards
+        let difficulty = orphan.difficulty.unwrap_or(Difficulty::Easy);
+        let changelog = format!("Imported from \"{}\"", orphan.path);
+        // invalid files are logged and stay where they are
+        if store_map(
+            db,
+            AuditEvent::new(UPLOADER, AuditAction::Import),
+            &orphan.name,
+            difficulty,
+            &file,
+            &orphan.path,
+            Some(&changelog),
+        )
+        .is_err()
+        {
+            continue;
+        }
+        let imported = find_map(db, &orphan.name)
+            .map(|(_id, map)| map.path())
+            .unwrap_or_default();
+        if imported != Path::new(&orphan.path) {
+            std::fs::remove_file(&orphan.path)
+                .map_err(to_internal_server_error)?;
+        }
+    }
+
+    update_votes(db)
+}
+
+/// Prints the report for the `reconcile` subcommand and fixes it if asked.
+pub fn run(db: &Structsy, fix_problems: bool) -> Result<(), CustomStatus> {
+    let report = reconcile(db)?;
+    if report.is_empty() {
+        println!("The database and the map folders agree.");
+        return Ok(());
+    }
+    if !report.missing.is_empty() {
+        println!("Maps without a file:");
+        for file in &report.missing {
+            println!("  {} (expected at \"{}\")", file.name, file.expected);
+        }
+    }
+    if !report.orphans.is_empty() {
+        println!("Files without a map:");
+        for file in &report.orphans {
+            println!("  \"{}\"", file.path);
+        }
+    }
+    if !report.misplaced.is_empty() {
+        println!("Misplaced files:");
+        for file in &report.misplaced {
+            match &file.expected {
+                Some(expected) => {
+                    println!("  \"{}\" belongs at \"{}\"", file.path, expected)
+                }
+                None => {
+                    println!("  \"{}\" belongs to an archived map", file.path)
+                }
+            }
+        }
+    }
+    if fix_problems {
+        fix(db, &report)?;
+    }
+    Ok(())
+}
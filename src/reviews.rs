@@ -0,0 +1,90 @@
+//! Testers review a map after playing it on the test servers. Every API key
+//! has at most one review per map, submitting again replaces it.
+
+use crate::Difficulty;
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use structsy::{Ref, Structsy};
+use structsy_derive::{queries, Persistent};
+
+/// The lowest and highest rating of a review.
+pub const RATINGS: std::ops::RangeInclusive<u8> = 1..=5;
+
+#[derive(Serialize, JsonSchema, Persistent, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Review {
+    /// The name of the reviewed map.
+    #[index]
+    pub map: String,
+    /// The identity of the API key which submitted the review.
+    pub reviewer: String,
+    pub rating: u8,
+    /// The difficulty the reviewer thinks the map belongs to.
+    pub difficulty: Option<Difficulty>,
+    pub notes: Option<String>,
+    pub created_at: u64,
+}
+
+#[queries(Review)]
+pub trait ReviewByMap {
+    fn by_map(self, map: &str) -> Self;
+}
+
+/// All reviews of the given map together with their ids, the oldest first.
+pub fn of_map(db: &Structsy, map: &str) -> Vec<(Ref<Review>, Review)> {
+    let query = db.query::<Review>().by_map(map);
+    let mut reviews = query.into_iter().collect::<Vec<_>>();
+    reviews.sort_by_key(|(_id, review)| review.created_at);
+    reviews
+}
+
+#[derive(Serialize, JsonSchema, Default, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ReviewSummary {
+    pub count: usize,
+    /// `None` without reviews.
+    pub average_rating: Option<f64>,
+    /// The difficulty suggested most often, `None` if none was suggested.
+    pub suggested_difficulty: Option<Difficulty>,
+}
+
+impl ReviewSummary {
+    pub fn of<'a>(reviews: impl IntoIterator<Item = &'a Review>) -> Self {
+        let mut count = 0;
+        let mut total = 0u64;
+        let mut suggestions = Vec::<(Difficulty, usize)>::new();
+        for review in reviews {
+            count += 1;
+            total += u64::from(review.rating);
+            if let Some(difficulty) = review.difficulty {
+                match suggestions.iter_mut().find(|(d, _)| *d == difficulty) {
+                    Some((_, n)) => *n += 1,
+                    None => suggestions.push((difficulty, 1)),
+                }
+            }
+        }
+        ReviewSummary {
+            count,
+            average_rating: (count > 0).then(|| total as f64 / count as f64),
+            // on a tie, the difficulty suggested first wins
+            suggested_difficulty: suggestions
+                .iter()
+                .rev()
+                .max_by_key(|(_, n)| *n)
+                .map(|(d, _)| *d),
+        }
+    }
+}
+
+/// The review summaries of all maps, by map name.
+pub fn summaries(db: &Structsy) -> HashMap<String, ReviewSummary> {
+    let mut by_map = HashMap::<String, Vec<Review>>::new();
+    for (_id, review) in db.query::<Review>().into_iter() {
+        by_map.entry(review.map.clone()).or_default().push(review);
+    }
+    by_map
+        .into_iter()
+        .map(|(map, reviews)| (map, ReviewSummary::of(&reviews)))
+        .collect()
+}
@@ -0,0 +1,70 @@
+//! Every uploaded map file is kept as an immutable revision. The files are
+//! stored content-addressed by their SHA-256 in the revision folder, so
+//! uploading the same file twice only stores it once.
+
+use crate::files;
+use crate::hashes::MapHashes;
+use crate::CONFIG;
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use std::path::PathBuf;
+use structsy::{Ref, Structsy};
+use structsy_derive::{queries, Persistent};
+
+#[derive(Serialize, JsonSchema, Persistent, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Revision {
+    /// The name of the map this revision belongs to.
+    #[index]
+    pub map: String,
+    pub sha256: String,
+    pub crc32: u32,
+    /// The identity of the API key which uploaded this revision.
+    pub uploader: String,
+    pub created_at: u64,
+    pub changelog: Option<String>,
+}
+
+#[queries(Revision)]
+pub trait RevisionByMap {
+    fn by_map(self, map: &str) -> Self;
+}
+
+impl Revision {
+    pub fn path(&self) -> PathBuf {
+        file_path(&self.sha256)
+    }
+}
+
+fn file_path(sha256: &str) -> PathBuf {
+    CONFIG.revision_folder.join(format!("{}.map", sha256))
+}
+
+/// Stores the file in the revision folder, unless a file with the same
+/// content is already stored.
+pub fn store_file(bytes: &[u8], hashes: &MapHashes) -> std::io::Result<()> {
+    let path = file_path(&hashes.sha256);
+    if !path.exists() {
+        files::write_atomic(path, bytes)?;
+    }
+    Ok(())
+}
+
+/// All revisions of the given map, the oldest first.
+pub fn list(db: &Structsy, map: &str) -> Vec<Revision> {
+    let query = db.query::<Revision>().by_map(map);
+    let mut revisions =
+        query.into_iter().map(|(_id, rev)| rev).collect::<Vec<_>>();
+    revisions.sort_by_key(|rev| rev.created_at);
+    revisions
+}
+
+/// Finds the revision of the given map with the given hash.
+pub fn find(db: &Structsy, map: &str, sha256: &str) -> Option<Revision> {
+    list(db, map).into_iter().find(|rev| rev.sha256 == sha256)
+}
+
+/// All revisions of the given map together with their ids, to change them.
+pub fn of_map(db: &Structsy, map: &str) -> Vec<(Ref<Revision>, Revision)> {
+    db.query::<Revision>().by_map(map).into_iter().collect()
+}
@@ -0,0 +1,174 @@
+//! Generates the map rotation of a server next to its votes, so newly
+//! published maps enter the rotation right away. Every target with a rotation
+//! gets a `maprotation.cfg` with the `sv_maprotation` line and a
+//! `maplist.txt` with one map per line, in the same order.
+
+use crate::{Map, MapState};
+use rocket::serde::Deserialize;
+use strum::EnumString;
+
+#[derive(Deserialize, EnumString, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RotationOrder {
+    /// The oldest maps first.
+    #[default]
+    CreatedAt,
+    /// Shuffled with a fixed seed, so the order only changes with the maps.
+    Shuffle,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct RotationConfig {
+    #[serde(default)]
+    pub order: RotationOrder,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// SplitMix64, as the order has to stay the same across releases of any
+/// random number crate.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Whether the server reads the name as a single map. `sv_maprotation` splits
+/// on spaces and quotes, semicolons or line breaks would end the command.
+fn is_safe(name: &str) -> bool {
+    !name.is_empty()
+        && !name.chars().any(|c| {
+            c.is_whitespace() || c.is_control() || matches!(c, '"' | ';' | '\\')
+        })
+}
+
+/// The name the server knows the map by, relative to its maps folder like in
+/// the default vote templates.
+fn entry(map: &Map) -> String {
+    if map.state == MapState::Published {
+        let difficulty: &str = map.difficulty.as_ref();
+        format!("{}/{}", difficulty, map.name)
+    } else {
+        map.name.clone()
+    }
+}
+
+impl RotationConfig {
+    /// The rotation of the maps, which are expected to be ordered by their
+    /// creation, the oldest first. Maps the server can't read from the
+    /// rotation are left out.
+    pub fn entries(&self, maps: &[&Map]) -> Vec<String> {
+        let mut entries = maps
+            .iter()
+            .filter(|map| {
+                let safe = is_safe(&map.name);
+                if !safe {
+                    eprintln!("Leaving \"{}\" out of the rotation", map.name);
+                }
+                safe
+            })
+            .map(|map| entry(map))
+            .collect::<Vec<_>>();
+        if self.order == RotationOrder::Shuffle {
+            let mut rng = SplitMix64(self.seed);
+            for i in (1..entries.len()).rev() {
+                let j = (rng.next() % (i as u64 + 1)) as usize;
+                entries.swap(i, j);
+            }
+        }
+        entries
+    }
+}
+
+/// The contents of `maprotation.cfg`.
+pub fn render_config(entries: &[String]) -> String {
+    format!("sv_maprotation \"{}\"\n", entries.join(" "))
+}
+
+/// The contents of `maplist.txt`.
+pub fn render_list(entries: &[String]) -> String {
+    entries.iter().map(|entry| format!("{}\n", entry)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Difficulty;
+
+    fn map(name: &str, state: MapState) -> Map {
+        Map {
+            name: name.to_owned(),
+            difficulty: Difficulty::Hard,
+            state,
+            created_at: 0,
+            last_changed: 0,
+            author: None,
+            version: None,
+            credits: None,
+            license: None,
+            sha256: None,
+            crc32: None,
+            tags: Vec::new(),
+            candidate: None,
+        }
+    }
+
+    fn entries(config: RotationConfig, maps: &[Map]) -> Vec<String> {
+        config.entries(&maps.iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn renders_config_and_list() {
+        let maps = [map("a", MapState::New), map("b", MapState::Published)];
+        let entries = entries(
+            RotationConfig {
+                order: RotationOrder::CreatedAt,
+                seed: 0,
+            },
+            &maps,
+        );
+        assert_eq!(render_config(&entries), "sv_maprotation \"a hard/b\"\n");
+        assert_eq!(render_list(&entries), "a\nhard/b\n");
+    }
+
+    #[test]
+    fn unsafe_names_are_left_out() {
+        let maps = [
+            map("a b", MapState::New),
+            map("a\"; shutdown; \"", MapState::New),
+            map("a\nshutdown", MapState::New),
+            map("a;b", MapState::New),
+            map("ok", MapState::New),
+        ];
+        let entries = entries(
+            RotationConfig {
+                order: RotationOrder::CreatedAt,
+                seed: 0,
+            },
+            &maps,
+        );
+        assert_eq!(render_config(&entries), "sv_maprotation \"ok\"\n");
+    }
+
+    #[test]
+    fn shuffle_is_stable() {
+        let maps = ["a", "b", "c", "d", "e", "f"]
+            .iter()
+            .map(|name| map(name, MapState::New))
+            .collect::<Vec<_>>();
+        let config = RotationConfig {
+            order: RotationOrder::Shuffle,
+            seed: 42,
+        };
+        // servers keep their rotation across releases
+        assert_eq!(entries(config, &maps), ["e", "d", "a", "c", "f", "b"]);
+    }
+}
@@ -0,0 +1,135 @@
+//! The life cycle of a map. Every state change goes through the transition
+//! table below, so adding a state or an action only means adding rows here.
+
+use crate::MapState;
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Action {
+    Approve,
+    Decline,
+    Publish,
+    /// Takes a map back to the test servers.
+    Recall,
+    /// Done by the purge of declined maps, not through the API.
+    Archive,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Transition {
+    pub action: Action,
+    pub from: &'static [MapState],
+    pub to: MapState,
+}
+
+pub const TRANSITIONS: &[Transition] = &[
+    Transition {
+        action: Action::Approve,
+        from: &[MapState::New, MapState::Declined],
+        to: MapState::Approved,
+    },
+    Transition {
+        action: Action::Decline,
+        from: &[MapState::New, MapState::Approved],
+        to: MapState::Declined,
+    },
+    Transition {
+        action: Action::Publish,
+        from: &[MapState::Approved],
+        to: MapState::Published,
+    },
+    // a recall also resets the approvals of a new map and brings archived
+    // maps back
+    Transition {
+        action: Action::Recall,
+        from: &MapState::ALL,
+        to: MapState::New,
+    },
+    Transition {
+        action: Action::Archive,
+        from: &[MapState::Declined],
+        to: MapState::Archived,
+    },
+];
+
+#[derive(Debug, derive_more::Display)]
+pub enum TransitionError {
+    #[display(fmt = "This map is already {:?}!", _0)]
+    Already(MapState),
+    #[display(fmt = "Cannot go from state {:?} to {:?}!", _0, _1)]
+    NotAllowed(MapState, MapState),
+    #[display(fmt = "Cannot {:?} a map in state {:?}!", _1, _0)]
+    NoTransition(MapState, Action),
+}
+
+impl std::error::Error for TransitionError {}
+
+/// The state a map in the given state ends up in after the action, from the
+/// first row of the action which starts in that state.
+pub fn transition(
+    state: MapState,
+    action: Action,
+) -> Result<MapState, TransitionError> {
+    let rows = || TRANSITIONS.iter().filter(|t| t.action == action);
+    if let Some(transition) = rows().find(|t| t.from.contains(&state)) {
+        Ok(transition.to)
+    } else if rows().any(|t| t.to == state) {
+        Err(TransitionError::Already(state))
+    } else if let Some(transition) = rows().next() {
+        Err(TransitionError::NotAllowed(state, transition.to))
+    } else {
+        Err(TransitionError::NoTransition(state, action))
+    }
+}
+
+/// The transitions a map in the given state can take.
+pub fn available(state: MapState) -> Vec<&'static Transition> {
+    TRANSITIONS
+        .iter()
+        .filter(|t| t.from.contains(&state))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_action_is_checked() {
+        assert_eq!(
+            transition(MapState::New, Action::Approve).unwrap(),
+            MapState::Approved
+        );
+        assert!(matches!(
+            transition(MapState::Approved, Action::Approve),
+            Err(TransitionError::Already(MapState::Approved))
+        ));
+        assert!(matches!(
+            transition(MapState::New, Action::Publish),
+            Err(TransitionError::NotAllowed(
+                MapState::New,
+                MapState::Published
+            ))
+        ));
+        assert!(matches!(
+            transition(MapState::Published, Action::Archive),
+            Err(TransitionError::NotAllowed(
+                MapState::Published,
+                MapState::Archived
+            ))
+        ));
+    }
+
+    #[test]
+    fn recalls_from_every_state() {
+        for state in MapState::ALL {
+            assert_eq!(
+                transition(state, Action::Recall).unwrap(),
+                MapState::New
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! API keys managed through the API. Only the SHA-256 of a key is stored, the
+//! key itself is shown once when it is created. The keys file stays the
+//! source of the first admin keys, see [`crate::keys`]. Both have labels of
+//! their own, a stored key acts as its label prefixed with [`LABEL_PREFIX`].
+
+use crate::apikey::Role;
+use crate::get_current_time;
+use crate::hashes::MapHashes;
+use rand_core::{OsRng, RngCore};
+use structsy::{Ref, Structsy, StructsyTx};
+use structsy_derive::{queries, Persistent};
+
+/// How often the last use of a key is written, to not write on every
+/// request.
+const LAST_USED_PRECISION: u64 = 60;
+/// Keeps a stored key from acting as a key of the keys file with the same
+/// label, the keys file may not use it.
+pub const LABEL_PREFIX: &str = "api:";
+
+#[derive(Persistent, Debug)]
+pub struct StoredApiKey {
+    #[index]
+    pub label: String,
+    pub roles: Vec<Role>,
+    #[index]
+    pub sha256: String,
+    /// The label of the key which created this one.
+    pub created_by: String,
+    pub created_at: u64,
+    pub last_used: Option<u64>,
+    /// The key stops working at this time, if set.
+    pub expires_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+#[queries(StoredApiKey)]
+pub trait StoredApiKeyQueries {
+    fn by_label(self, label: &str) -> Self;
+    fn by_sha256(self, sha256: &str) -> Self;
+}
+
+impl StoredApiKey {
+    /// The label the key acts as.
+    pub fn actor(&self) -> String {
+        format!("{}{}", LABEL_PREFIX, self.label)
+    }
+
+    pub fn is_valid(&self, now: u64) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// A new random key, as hex.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn secret_hash(secret: &str) -> String {
+    MapHashes::of(secret.as_bytes()).sha256
+}
+
+/// Finds a key by its label, with or without [`LABEL_PREFIX`].
+pub fn find_by_label(
+    db: &Structsy,
+    label: &str,
+) -> Option<(Ref<StoredApiKey>, StoredApiKey)> {
+    let label = label.strip_prefix(LABEL_PREFIX).unwrap_or(label);
+    db.query::<StoredApiKey>().by_label(label).fetch().next()
+}
+
+/// All stored keys, the oldest first.
+pub fn list(db: &Structsy) -> Vec<StoredApiKey> {
+    let mut keys = db
+        .query::<StoredApiKey>()
+        .into_iter()
+        .map(|(_id, key)| key)
+        .collect::<Vec<_>>();
+    keys.sort_by_key(|key| key.created_at);
+    keys
+}
+
+/// The prefixed label and the roles of the key, if it is stored and valid.
+/// Notes when the key was used.
+pub fn find(db: &Structsy, key: &str) -> Option<(String, Vec<Role>)> {
+    let (id, stored) = db
+        .query::<StoredApiKey>()
+        .by_sha256(&secret_hash(key))
+        .fetch()
+        .next()?;
+    let now = get_current_time().ok()?;
+    if !stored.is_valid(now) {
+        return None;
+    }
+    let found = (stored.actor(), stored.roles.clone());
+    if stored
+        .last_used
+        .is_none_or(|last_used| last_used + LAST_USED_PRECISION <= now)
+    {
+        let used = StoredApiKey {
+            last_used: Some(now),
+            ..stored
+        };
+        // the key is valid even if noting its use fails
+        let _ = db.begin().and_then(|mut tx| {
+            tx.update(&id, &used)?;
+            tx.commit()
+        });
+    }
+    Some(found)
+}
@@ -0,0 +1,174 @@
+//! Every game server has a target, which decides the maps in its vote file,
+//! where the file is written and how it looks. The targets are read from a
+//! TOML file, see `templates/targets.toml`. Without one, there is a target
+//! for the test folder and one with a map rotation for every difficulty
+//! folder.
+
+use crate::econ::EconConfig;
+use crate::options::Options;
+use crate::rotation::RotationConfig;
+use crate::votes::{TemplateError, VoteTemplate};
+use crate::{Difficulty, Map, MapState};
+use rocket::serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, derive_more::Display)]
+pub enum TargetError {
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "{}", _0)]
+    Toml(toml::de::Error),
+    #[display(fmt = "template of target \"{}\": {}", _0, _1)]
+    Template(String, TemplateError),
+}
+
+impl std::error::Error for TargetError {}
+
+fn published() -> Vec<MapState> {
+    vec![MapState::Published]
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+struct TargetConfig {
+    name: String,
+    /// The folder the `votes.cfg` is written to.
+    output: PathBuf,
+    #[serde(default = "published")]
+    states: Vec<MapState>,
+    /// All difficulties if empty.
+    #[serde(default)]
+    difficulties: Vec<Difficulty>,
+    /// Maps need at least one of the tags, if there are any.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// The published vote template if not set.
+    template: Option<PathBuf>,
+    /// The external console of the server, to load new votes right away.
+    econ: Option<EconConfig>,
+    /// Writes a map rotation next to the votes, if set.
+    rotation: Option<RotationConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+struct TargetsFile {
+    #[serde(rename = "target")]
+    targets: Vec<TargetConfig>,
+}
+
+pub struct Target {
+    pub name: String,
+    pub output: PathBuf,
+    states: Vec<MapState>,
+    difficulties: Vec<Difficulty>,
+    tags: Vec<String>,
+    pub template: VoteTemplate,
+    pub econ: Option<EconConfig>,
+    pub rotation: Option<RotationConfig>,
+}
+
+impl Target {
+    pub fn matches(&self, map: &Map) -> bool {
+        self.states.contains(&map.state)
+            && (self.difficulties.is_empty()
+                || self.difficulties.contains(&map.difficulty))
+            && (self.tags.is_empty()
+                || self.tags.iter().any(|tag| map.tags.contains(tag)))
+    }
+
+    /// The path of the generated `sv_maprotation` config.
+    pub fn rotation_file(&self) -> PathBuf {
+        self.output.join("maprotation.cfg")
+    }
+
+    /// The path of the generated map list.
+    pub fn map_list_file(&self) -> PathBuf {
+        self.output.join("maplist.txt")
+    }
+
+    /// The path of the rendered vote file.
+    pub fn votes_file(&self) -> PathBuf {
+        self.output.join("votes.cfg")
+    }
+}
+
+fn default_targets(options: &Options) -> Result<Vec<Target>, TargetError> {
+    let template_error = |name: &str| {
+        let name = name.to_owned();
+        move |e| TargetError::Template(name, e)
+    };
+    let mut targets = vec![Target {
+        name: "test".to_owned(),
+        output: options.test_maps.clone(),
+        states: vec![MapState::New, MapState::Approved, MapState::Declined],
+        difficulties: Vec::new(),
+        tags: Vec::new(),
+        template: VoteTemplate::load_test(options.test_vote_template.as_ref())
+            .map_err(template_error("test"))?,
+        econ: None,
+        rotation: None,
+    }];
+    for difficulty in Difficulty::ALL {
+        let name: &str = difficulty.as_ref();
+        targets.push(Target {
+            name: name.to_owned(),
+            output: options.published_maps.join(difficulty),
+            states: published(),
+            difficulties: vec![difficulty],
+            tags: Vec::new(),
+            template: VoteTemplate::load_published(
+                options.published_vote_template.as_ref(),
+            )
+            .map_err(template_error(name))?,
+            econ: None,
+            rotation: Some(RotationConfig {
+                order: options.rotation_order,
+                seed: options.rotation_seed,
+            }),
+        });
+    }
+    Ok(targets)
+}
+
+fn read_targets(
+    path: &Path,
+    options: &Options,
+) -> Result<Vec<Target>, TargetError> {
+    let text = std::fs::read_to_string(path).map_err(TargetError::Io)?;
+    let file: TargetsFile = toml::from_str(&text).map_err(TargetError::Toml)?;
+    file.targets
+        .into_iter()
+        .map(|config| {
+            let template = config
+                .template
+                .as_ref()
+                .or(options.published_vote_template.as_ref());
+            let template = VoteTemplate::load_published(template)
+                .map_err(|e| TargetError::Template(config.name.clone(), e))?;
+            Ok(Target {
+                name: config.name,
+                output: config.output,
+                states: config.states,
+                difficulties: config.difficulties,
+                tags: config
+                    .tags
+                    .iter()
+                    .map(|tag| tag.to_lowercase())
+                    .collect(),
+                template,
+                econ: config.econ,
+                rotation: config.rotation,
+            })
+        })
+        .collect()
+}
+
+/// Loads the targets from the file given in the options, or the default
+/// targets if there is none.
+pub fn load(options: &Options) -> Result<Vec<Target>, TargetError> {
+    match &options.targets {
+        Some(path) => read_targets(path, options),
+        None => default_targets(options),
+    }
+}
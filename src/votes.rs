@@ -0,0 +1,518 @@
+//! Renders the `votes.cfg` files of the game servers from templates, so every
+//! server can have its own vote layout. The format is described in the
+//! default templates in the `templates` folder.
+//!
+//! Field values come from uploaded map files and users, so they are escaped
+//! for the quotes around them: once in a quoted argument, twice in a command
+//! quoted inside of one, and so on. Control characters are dropped.
+
+use crate::{Difficulty, Map, MapState, CONFIG};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+const DEFAULT_TEST_TEMPLATE: &str =
+    include_str!("../templates/test-votes.tmpl");
+const DEFAULT_PUBLISHED_TEMPLATE: &str =
+    include_str!("../templates/published-votes.tmpl");
+
+#[derive(Debug, derive_more::Display)]
+pub enum TemplateError {
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "line {}: unknown section [{}]", _0, _1)]
+    UnknownSection(usize, String),
+    #[display(fmt = "line {}: text outside of a section", _0)]
+    NoSection(usize),
+    #[display(fmt = "line {}: invalid setting \"{}\"", _0, _1)]
+    InvalidSetting(usize, String),
+    #[display(fmt = "line {}: unknown field \"{}\"", _0, _1)]
+    UnknownField(usize, String),
+    #[display(
+        fmt = "line {}: fields are only available in [new_map] and [map]",
+        _0
+    )]
+    FieldOutsideOfMap(usize),
+    #[display(fmt = "line {}: unclosed field", _0)]
+    UnclosedField(usize),
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Name,
+    Difficulty,
+    DifficultyLabel,
+    State,
+    StateLabel,
+    Author,
+    Version,
+    Credits,
+    License,
+    Sha256,
+    Crc32,
+    Folder,
+}
+
+impl FromStr for Field {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use Field::*;
+        Ok(match s {
+            "name" => Name,
+            "difficulty" => Difficulty,
+            "difficulty_label" => DifficultyLabel,
+            "state" => State,
+            "state_label" => StateLabel,
+            "author" => Author,
+            "version" => Version,
+            "credits" => Credits,
+            "license" => License,
+            "sha256" => Sha256,
+            "crc32" => Crc32,
+            "folder" => Folder,
+            _ => return Err(()),
+        })
+    }
+}
+
+enum Segment {
+    Text(String),
+    Field {
+        field: Field,
+        /// Pads the value to the width, aligned to the left or the right.
+        width: Option<(char, usize)>,
+        /// How many quotes are open around the field.
+        depth: usize,
+    },
+}
+
+/// The number of backslashes before the quotes which open and close a string
+/// nested `depth` levels deep: `"`, `\"`, `\\\"` and so on.
+fn quote_escapes(depth: usize) -> usize {
+    2usize.saturating_pow(depth as u32 - 1) - 1
+}
+
+/// The number of quotes open after the text, given the number open before.
+fn quote_depth(text: &str, mut depth: usize) -> usize {
+    let mut backslashes = 0;
+    for c in text.chars() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            }
+            '"' if depth > 0 && backslashes == quote_escapes(depth) => {
+                depth -= 1
+            }
+            '"' if backslashes == quote_escapes(depth + 1) => depth += 1,
+            _ => {}
+        }
+        backslashes = 0;
+    }
+    depth
+}
+
+/// Escapes the value for a string nested `depth` quotes deep. Outside of
+/// quotes, everything which could start a string or another command is
+/// dropped.
+fn escape(value: &str, depth: usize) -> String {
+    let value = value.chars().filter(|c| !c.is_control());
+    if depth == 0 {
+        return value.filter(|c| !matches!(c, '"' | '\\' | ';')).collect();
+    }
+    let mut escaped = value.collect::<String>();
+    for _ in 0..depth {
+        escaped = escaped.replace('\\', "\\\\").replace('"', "\\\"");
+    }
+    escaped
+}
+
+/// A line of a section.
+struct Line(Vec<Segment>);
+
+impl Line {
+    fn parse(number: usize, line: &str) -> Result<Line, TemplateError> {
+        let mut segments = Vec::new();
+        let mut rest = line;
+        let mut depth = 0;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                depth = quote_depth(&rest[..start], depth);
+                segments.push(Segment::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or(TemplateError::UnclosedField(number))?;
+            let spec = rest[start + 2..start + end].trim();
+            let (name, width) = match spec.split_once(':') {
+                Some((name, width)) => {
+                    let mut chars = width.chars();
+                    let align = chars.next().filter(|c| *c == '<' || *c == '>');
+                    let width = chars.as_str().parse().ok();
+                    match (align, width) {
+                        (Some(align), Some(width)) => {
+                            (name, Some((align, width)))
+                        }
+                        _ => {
+                            return Err(TemplateError::UnknownField(
+                                number,
+                                spec.to_owned(),
+                            ))
+                        }
+                    }
+                }
+                None => (spec, None),
+            };
+            let field = name.parse().map_err(|_| {
+                TemplateError::UnknownField(number, spec.to_owned())
+            })?;
+            segments.push(Segment::Field {
+                field,
+                width,
+                depth,
+            });
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_owned()));
+        }
+        Ok(Line(segments))
+    }
+
+    fn has_fields(&self) -> bool {
+        self.0.iter().any(|s| matches!(s, Segment::Field { .. }))
+    }
+
+    fn render(&self, template: &VoteTemplate, map: Option<&Map>) -> String {
+        let mut out = String::new();
+        for segment in &self.0 {
+            match (segment, map) {
+                (Segment::Text(text), _) => out.push_str(text),
+                (
+                    Segment::Field {
+                        field,
+                        width,
+                        depth,
+                    },
+                    Some(map),
+                ) => {
+                    let value = template.field(*field, map);
+                    let value = match width {
+                        Some(('>', width)) => format!("{:>1$}", value, width),
+                        Some((_, width)) => format!("{:<1$}", value, width),
+                        None => value,
+                    };
+                    out.push_str(&escape(&value, *depth));
+                }
+                // fields outside of map sections are rejected when parsing
+                (Segment::Field { .. }, None) => {}
+            }
+        }
+        out
+    }
+}
+
+/// How the maps after the new ones are ordered.
+#[derive(Clone, Copy, PartialEq)]
+enum Sort {
+    CreatedAt,
+    Name,
+}
+
+pub struct VoteTemplate {
+    /// How many of the newest maps are listed in [new_map] instead of [map].
+    new_maps: usize,
+    sort: Sort,
+    state_labels: HashMap<String, String>,
+    difficulty_labels: HashMap<String, String>,
+    header: Vec<Line>,
+    new: Vec<Line>,
+    new_map: Option<Vec<Line>>,
+    separator: Vec<Line>,
+    map: Vec<Line>,
+    footer: Vec<Line>,
+}
+
+/// Removes the quotes around a setting value, which keep its spaces.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+impl VoteTemplate {
+    pub fn parse(text: &str) -> Result<VoteTemplate, TemplateError> {
+        let mut template = VoteTemplate {
+            new_maps: 0,
+            sort: Sort::CreatedAt,
+            state_labels: HashMap::new(),
+            difficulty_labels: HashMap::new(),
+            header: Vec::new(),
+            new: Vec::new(),
+            new_map: None,
+            separator: Vec::new(),
+            map: Vec::new(),
+            footer: Vec::new(),
+        };
+        let mut section = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let trimmed = line.trim();
+            // comments in the other sections are copied, the game server
+            // ignores them as well
+            if trimmed.is_empty()
+                || (trimmed.starts_with('#')
+                    && section.is_none_or(|s| s == "settings"))
+            {
+                continue;
+            }
+            if let Some(name) =
+                trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']'))
+            {
+                match name {
+                    "settings" | "header" | "new" | "new_map" | "separator"
+                    | "map" | "footer" => section = Some(name),
+                    _ => {
+                        return Err(TemplateError::UnknownSection(
+                            number,
+                            name.to_owned(),
+                        ))
+                    }
+                }
+                if name == "new_map" {
+                    template.new_map.get_or_insert_with(Vec::new);
+                }
+                continue;
+            }
+
+            let section = section.ok_or(TemplateError::NoSection(number))?;
+            if section == "settings" {
+                template.set(number, trimmed)?;
+                continue;
+            }
+            let line = Line::parse(number, line)?;
+            let lines = match section {
+                "header" => &mut template.header,
+                "new" => &mut template.new,
+                "separator" => &mut template.separator,
+                "footer" => &mut template.footer,
+                "map" => &mut template.map,
+                _ => template.new_map.get_or_insert_with(Vec::new),
+            };
+            if line.has_fields() && section != "map" && section != "new_map" {
+                return Err(TemplateError::FieldOutsideOfMap(number));
+            }
+            lines.push(line);
+        }
+
+        Ok(template)
+    }
+
+    fn set(&mut self, number: usize, line: &str) -> Result<(), TemplateError> {
+        let invalid = || TemplateError::InvalidSetting(number, line.to_owned());
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let (key, value) = (key.trim(), unquote(value.trim()));
+        if let Some(state) = key.strip_prefix("state.") {
+            MapState::from_str(state).map_err(|_| invalid())?;
+            self.state_labels.insert(state.to_owned(), value.to_owned());
+        } else if let Some(difficulty) = key.strip_prefix("difficulty.") {
+            Difficulty::from_str(difficulty).map_err(|_| invalid())?;
+            self.difficulty_labels
+                .insert(difficulty.to_owned(), value.to_owned());
+        } else if key == "new_maps" {
+            self.new_maps = value.parse().map_err(|_| invalid())?;
+        } else if key == "sort" {
+            self.sort = match value {
+                "name" => Sort::Name,
+                "created_at" => Sort::CreatedAt,
+                _ => return Err(invalid()),
+            };
+        } else {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// Loads the template at the given path, or the given default if there is
+    /// none.
+    pub fn load<P: AsRef<Path>>(
+        path: Option<P>,
+        default: &str,
+    ) -> Result<VoteTemplate, TemplateError> {
+        match path {
+            Some(path) => VoteTemplate::parse(
+                &std::fs::read_to_string(path).map_err(TemplateError::Io)?,
+            ),
+            None => VoteTemplate::parse(default),
+        }
+    }
+
+    pub fn load_test<P: AsRef<Path>>(
+        path: Option<P>,
+    ) -> Result<VoteTemplate, TemplateError> {
+        VoteTemplate::load(path, DEFAULT_TEST_TEMPLATE)
+    }
+
+    pub fn load_published<P: AsRef<Path>>(
+        path: Option<P>,
+    ) -> Result<VoteTemplate, TemplateError> {
+        VoteTemplate::load(path, DEFAULT_PUBLISHED_TEMPLATE)
+    }
+
+    fn field(&self, field: Field, map: &Map) -> String {
+        let optional =
+            |value: &Option<String>| value.clone().unwrap_or_default();
+        let difficulty: &str = map.difficulty.as_ref();
+        match field {
+            Field::Name => map.name.clone(),
+            Field::Difficulty => difficulty.to_owned(),
+            Field::DifficultyLabel => self
+                .difficulty_labels
+                .get(difficulty)
+                .cloned()
+                .unwrap_or_else(|| map.difficulty.to_string()),
+            Field::State => map.state.as_ref().to_owned(),
+            Field::StateLabel => self
+                .state_labels
+                .get(map.state.as_ref())
+                .cloned()
+                .unwrap_or_else(|| map.state.as_ref().to_owned()),
+            Field::Author => optional(&map.author),
+            Field::Version => optional(&map.version),
+            Field::Credits => optional(&map.credits),
+            Field::License => optional(&map.license),
+            Field::Sha256 => optional(&map.sha256),
+            Field::Crc32 => map
+                .crc32
+                .map(|crc| format!("{:08x}", crc))
+                .unwrap_or_default(),
+            Field::Folder => CONFIG
+                .public_map_folder
+                .join(map.difficulty)
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Renders the vote file for the maps, which are expected to be ordered
+    /// by their creation, the oldest first. The new maps are listed the
+    /// newest first.
+    pub fn render(&self, maps: &[&Map]) -> String {
+        let static_lines = |lines: &[Line]| {
+            lines
+                .iter()
+                .map(|l| l.render(self, None))
+                .collect::<Vec<_>>()
+        };
+        let map_lines = |lines: &[Line], map: &Map| {
+            lines
+                .iter()
+                .map(|l| l.render(self, Some(map)))
+                .collect::<Vec<_>>()
+        };
+
+        let (other, new) =
+            maps.split_at(maps.len() - self.new_maps.min(maps.len()));
+        let mut other = other.to_vec();
+        if self.sort == Sort::Name {
+            other.sort_by_key(|m| &m.name);
+        }
+
+        let mut text = static_lines(&self.header);
+        if self.new_maps > 0 {
+            let new_map = self.new_map.as_ref().unwrap_or(&self.map);
+            text.extend(static_lines(&self.new));
+            for map in new.iter().rev() {
+                text.extend(map_lines(new_map, map));
+            }
+            text.extend(static_lines(&self.separator));
+        }
+        for map in other {
+            text.extend(map_lines(&self.map, map));
+        }
+        text.extend(static_lines(&self.footer));
+        text.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(name: &str, created_at: u64) -> Map {
+        Map {
+            name: name.to_owned(),
+            difficulty: Difficulty::Easy,
+            state: MapState::New,
+            created_at,
+            last_changed: created_at,
+            author: None,
+            version: None,
+            credits: None,
+            license: None,
+            sha256: None,
+            crc32: None,
+            tags: Vec::new(),
+            candidate: None,
+        }
+    }
+
+    fn render(template: &str, maps: &[Map]) -> String {
+        VoteTemplate::parse(template)
+            .unwrap()
+            .render(&maps.iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn quote_depth_follows_nested_quotes() {
+        assert_eq!(quote_depth(r#"add_vote ""#, 0), 1);
+        assert_eq!(quote_depth(r#"add_vote "a" "b"#, 0), 1);
+        assert_eq!(quote_depth(r#"add_vote "a" "change_map \""#, 0), 2);
+        assert_eq!(quote_depth(r#"\"""#, 2), 0);
+        assert_eq!(quote_depth(r#"say "\\\"x"#, 0), 1);
+    }
+
+    #[test]
+    fn quotes_in_fields_are_escaped() {
+        let template = concat!(
+            "[map]\n",
+            r#"add_vote "{{author}}" "change_map \"{{name}}\"""#
+        );
+        let mut injected = map(r#"a"; quit; ""#, 0);
+        injected.author = Some(r#"x\" "shutdown"#.to_owned());
+        assert_eq!(
+            render(template, &[injected]),
+            r#"add_vote "x\\\" \"shutdown" "change_map \"a\\\"; quit; \\\"\"""#
+        );
+    }
+
+    #[test]
+    fn control_characters_are_dropped() {
+        let mut map = map("a", 0);
+        map.credits = Some("line\nquit\r\t".to_owned());
+        assert_eq!(
+            render("[map]\nadd_vote \"{{credits}}\" \"info\"", &[map]),
+            r#"add_vote "linequit" "info""#
+        );
+    }
+
+    #[test]
+    fn unquoted_fields_cannot_start_commands() {
+        let mut map = map("a", 0);
+        map.license = Some(r#"x; quit "y\"#.to_owned());
+        assert_eq!(render("[map]\nsay {{license}}", &[map]), "say x quit y");
+    }
+
+    #[test]
+    fn new_maps_are_the_newest_first() {
+        let template = "[settings]\nnew_maps = 2\nsort = name\n\
+            [new]\nnew\n[separator]\n--\n[map]\n{{name}}";
+        let maps = [map("c", 1), map("a", 2), map("d", 3), map("b", 4)];
+        assert_eq!(render(template, &maps), "new\nb\nd\n--\na\nc");
+    }
+}